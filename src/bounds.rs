@@ -0,0 +1,201 @@
+use nalgebra::{Matrix4, Point3, Vector3, Vector4};
+
+use crate::picking::Ray;
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+impl Aabb {
+    /// Box containing no point at all, the identity of `merge`.
+    pub const EMPTY: Aabb = Aabb {
+        min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+        max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+    };
+
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Self {
+        points.into_iter().fold(Self::EMPTY, |aabb, point| Aabb {
+            min: aabb.min.inf(&point),
+            max: aabb.max.sup(&point),
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+    pub fn merge(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+    pub fn center(&self) -> Point3<f32> {
+        nalgebra::center(&self.min, &self.max)
+    }
+    pub fn size(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            )
+        })
+    }
+    /// Box containing the transformed box, larger than needed under rotation.
+    pub fn transform(&self, tm: &Matrix4<f32>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        Aabb::from_points(self.corners().map(|corner| tm.transform_point(&corner)))
+    }
+    /// Ray parameters where the ray enters and leaves the box, by the slab method.
+    pub fn intersect_ray(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let mut t_min = f32::NEG_INFINITY;
+        let mut t_max = f32::INFINITY;
+        for axis in 0..3 {
+            let inverse_direction = 1.0 / ray.direction[axis];
+            let t_1 = (self.min[axis] - ray.origin[axis]) * inverse_direction;
+            let t_2 = (self.max[axis] - ray.origin[axis]) * inverse_direction;
+            t_min = t_min.max(t_1.min(t_2));
+            t_max = t_max.min(t_1.max(t_2));
+        }
+        (t_min <= t_max && t_max >= 0.0).then_some((t_min, t_max))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+impl BoundingSphere {
+    /// Bounds of no point at all, such as of an empty mesh, a point at the origin so that culling
+    /// it stays well defined.
+    pub const EMPTY: BoundingSphere = BoundingSphere {
+        center: Point3::new(0.0, 0.0, 0.0),
+        radius: 0.0,
+    };
+
+    /// Sphere centered on the box of the points, not the smallest one but close enough.
+    pub fn from_points(points: &[Point3<f32>]) -> Self {
+        if points.is_empty() {
+            return Self::EMPTY;
+        }
+        let center = Aabb::from_points(points.iter().copied()).center();
+        let radius = points
+            .iter()
+            .map(|point| (point - center).norm())
+            .fold(0.0, f32::max);
+        Self { center, radius }
+    }
+    /// Sphere through the corners of the box.
+    pub fn from_aabb(aabb: &Aabb) -> Self {
+        if aabb.is_empty() {
+            return Self::EMPTY;
+        }
+        Self {
+            center: aabb.center(),
+            radius: aabb.size().norm() / 2.0,
+        }
+    }
+    /// Sphere containing the transformed sphere, scaled by the largest scaling of the transform.
+    pub fn transform(&self, tm: &Matrix4<f32>) -> BoundingSphere {
+        let scaling = (0..3)
+            .map(|axis| tm.fixed_view::<3, 1>(0, axis).norm())
+            .fold(0.0, f32::max);
+        BoundingSphere {
+            center: tm.transform_point(&self.center),
+            radius: self.radius * scaling,
+        }
+    }
+}
+
+/// Bounding volumes of a mesh, in model space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
+}
+impl Bounds {
+    /// Bounds of flat `[x, y, z, x, y, z, ...]` positions, as stored by tobj.
+    pub fn from_positions(positions: &[f32]) -> Self {
+        let points = positions
+            .chunks_exact(3)
+            .map(|position| Point3::new(position[0], position[1], position[2]))
+            .collect::<Vec<_>>();
+        Self {
+            aabb: Aabb::from_points(points.iter().copied()),
+            sphere: BoundingSphere::from_points(&points),
+        }
+    }
+    pub fn transform(&self, tm: &Matrix4<f32>) -> Bounds {
+        Bounds {
+            aabb: self.aabb.transform(tm),
+            sphere: self.sphere.transform(tm),
+        }
+    }
+}
+
+/// Planes of a view volume, pointing inwards, as `normal · point + distance`.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+impl Frustum {
+    /// Extracts the planes of a view projection matrix, such as `Camera::tm`.
+    pub fn from_matrix(tm: &Matrix4<f32>) -> Self {
+        let row = |i: usize| tm.row(i).transpose();
+        // nalgebra's perspective maps the near plane to a depth of -1
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(3) + row(2),
+            row(3) - row(2),
+        ]
+        .map(|plane| plane / plane.xyz().norm());
+        Self { planes }
+    }
+    pub fn planes(&self) -> [Vector4<f32>; 6] {
+        self.planes
+    }
+    fn distance(plane: &Vector4<f32>, point: &Point3<f32>) -> f32 {
+        plane.xyz().dot(&point.coords) + plane.w
+    }
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| Self::distance(plane, &sphere.center) >= -sphere.radius)
+    }
+    /// Whether the box may be inside, only testing its corner furthest along each plane.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let corner = Point3::new(
+                if plane.x >= 0.0 {
+                    aabb.max.x
+                } else {
+                    aabb.min.x
+                },
+                if plane.y >= 0.0 {
+                    aabb.max.y
+                } else {
+                    aabb.min.y
+                },
+                if plane.z >= 0.0 {
+                    aabb.max.z
+                } else {
+                    aabb.min.z
+                },
+            );
+            Self::distance(plane, &corner) >= 0.0
+        })
+    }
+    pub fn intersects(&self, bounds: &Bounds) -> bool {
+        self.intersects_sphere(&bounds.sphere) && self.intersects_aabb(&bounds.aabb)
+    }
+}
@@ -0,0 +1,9 @@
+// Objects of the scene, shared by the shaders drawing and culling them
+
+struct ObjectData {
+  modelMatrix: mat4x4<f32>,  // Position, rotation, scale
+  normalMatrix: mat3x3<f32>, // Inverse transpose of the model matrix's rotation and scale
+  diffuse: vec4<f32>,        // Diffuse color, alpha being the material dissolve
+  textureIndex: u32,         // Which texture in the array to use, 0 is plain white
+};
+@group(0) @binding(0) var<storage, read> objects: array<ObjectData>;
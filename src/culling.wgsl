@@ -0,0 +1,59 @@
+#include "common.wgsl"
+
+struct CullInput {
+  sphere: vec4<f32>, // Center and radius, in model space
+  indexCount: u32,
+  objectIdx: u32,     // Of the first instance, the others follow. Drawn as the first instance
+  instanceCount: u32,
+  visible: u32,       // 0 when the model is hidden
+  firstIndex: u32,    // Range of the mesh in the geometry pool
+  baseVertex: i32,
+};
+// Layout of wgpu's DrawIndexedIndirectArgs
+struct DrawIndexedIndirect {
+  indexCount: u32,
+  instanceCount: u32,
+  firstIndex: u32,
+  baseVertex: i32,
+  firstInstance: u32,
+};
+
+@group(0) @binding(1) var<storage, read> inputs: array<CullInput>;
+@group(0) @binding(2) var<uniform> frustum: array<vec4<f32>, 6>; // Planes pointing inwards
+@group(0) @binding(3) var<storage, read_write> draws: array<DrawIndexedIndirect>;
+
+@compute @workgroup_size(64)
+fn cs_cull(@builtin(global_invocation_id) id: vec3<u32>) {
+    let drawIdx = id.x;
+    if drawIdx >= arrayLength(&inputs) {
+        return;
+    }
+    let input = inputs[drawIdx];
+    var visible = false;
+    for (var instanceIdx = 0u; instanceIdx < input.instanceCount; instanceIdx++) {
+        if isSphereInFrustum(objects[input.objectIdx + instanceIdx].modelMatrix, input.sphere) {
+            visible = true;
+            break;
+        }
+    }
+    visible = visible && input.visible != 0u;
+    draws[drawIdx] = DrawIndexedIndirect(
+        input.indexCount,
+        select(0u, input.instanceCount, visible),
+        input.firstIndex,
+        input.baseVertex,
+        input.objectIdx,
+    );
+}
+
+fn isSphereInFrustum(modelMatrix: mat4x4<f32>, sphere: vec4<f32>) -> bool {
+    let center = (modelMatrix * vec4<f32>(sphere.xyz, 1.0)).xyz;
+    let scaling = max(length(modelMatrix[0].xyz), max(length(modelMatrix[1].xyz), length(modelMatrix[2].xyz)));
+    let radius = sphere.w * scaling;
+    for (var i = 0u; i < 6u; i++) {
+        if dot(frustum[i].xyz, center) + frustum[i].w < -radius {
+            return false;
+        }
+    }
+    return true;
+}
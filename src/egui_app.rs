@@ -1,17 +1,43 @@
-use std::{borrow::Cow, f32::consts::PI};
+use std::{borrow::Cow, fs, sync::Arc};
 
 use eframe::wgpu;
-use egui::{Sense, Slider};
-use egui_wgpu::CallbackTrait;
+use egui::{ComboBox, DragValue, Sense, Slider, TextureId};
+use egui_wgpu::{RenderState, WgpuSetup, WgpuSetupCreateNew};
+use itertools::Itertools;
+use log::info;
 use nalgebra::Vector3;
 
 use crate::{
-    models::{Camera, Model},
-    renderer::Renderer,
+    gizmo::{Gizmo, GizmoMode, GizmoSpace},
+    hot_reload::{self, ModelWatcher},
+    models::{MaterialExt, Model},
+    picking::{self, Ray},
+    primitives::Primitive,
+    renderer::{self, CullingMode, DebugView, RenderTarget, Renderer},
+    scene::{Scene, Selection},
+    terrain::{Heights, Terrain},
 };
 
+/// Color format of the offscreen viewport, sampled by egui as a regular sRGB texture.
+const VIEWPORT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+const VIEWPORT_SAMPLE_COUNT: u32 = 4;
+const BOUNDS_COLOR: [f32; 4] = [1.0, 0.9, 0.2, 1.0];
+
 pub fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let native_options = eframe::NativeOptions::default();
+    let mut native_options = eframe::NativeOptions::default();
+    native_options.wgpu_options.wgpu_setup = WgpuSetup::CreateNew(WgpuSetupCreateNew {
+        device_descriptor: Arc::new(|adapter| wgpu::DeviceDescriptor {
+            label: Some("egui wgpu device"),
+            required_features: renderer::REQUIRED_FEATURES
+                | (adapter.features() & renderer::OPTIONAL_FEATURES),
+            required_limits: wgpu::Limits {
+                max_texture_dimension_2d: 8192,
+                ..adapter.limits()
+            },
+            memory_hints: wgpu::MemoryHints::default(),
+        }),
+        ..Default::default()
+    });
     eframe::run_native(
         "My egui App",
         native_options,
@@ -20,104 +46,552 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Offscreen render target of the central panel and its egui texture handle.
+struct Viewport {
+    render_target: RenderTarget,
+    texture_id: TextureId,
+}
+
 pub struct MyEguiApp {
-    models: Vec<Model>,
-    camera: Camera,
+    scene: Scene,
+    render_state: RenderState,
+    renderer: Renderer<'static>,
+    viewport: Option<Viewport>,
+    resolution_scale: f32,
+    gizmo: Gizmo,
+    show_bounds: bool,
+    model_watcher: ModelWatcher,
+    /// Of the last model added from a file, until one is added successfully.
+    add_error: Option<String>,
 }
 
 impl MyEguiApp {
-    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
+    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         // Customize egui here with cc.egui_ctx.set_fonts and cc.egui_ctx.set_visuals.
         // Restore app state using cc.storage (requires the "persistence" feature).
-        // Use the cc.gl (a glow::Context) to create graphics shaders and buffers that you can use
-        // for e.g. egui::PaintCallback.
-        let models = vec![Model::new(
-            "./models/cube.obj",
-            (Vector3::default(), Vector3::default(), Vector3::default()),
-        )];
-        let camera = Camera::new(1.0);
-
-        Self { models, camera }
+        let scene = Scene::new([1, 1]);
+        let render_state = cc.wgpu_render_state.clone().unwrap();
+        let mut renderer = Renderer::new(
+            Cow::Owned(render_state.device.clone()),
+            Cow::Owned(render_state.queue.clone()),
+            &render_state.adapter.get_info(),
+            VIEWPORT_FORMAT,
+            VIEWPORT_SAMPLE_COUNT,
+        );
+        renderer.load_scene(&scene);
+
+        Self {
+            scene,
+            render_state,
+            renderer,
+            viewport: None,
+            resolution_scale: 1.0,
+            gizmo: Gizmo::default(),
+            show_bounds: false,
+            model_watcher: ModelWatcher::default(),
+            add_error: None,
+        }
+    }
+    /// Adds a model to the scene and selects it, or reports why its textures failed to load.
+    fn add_model(&mut self, model: Model) {
+        match self.scene.add_model(model) {
+            Ok(()) => {
+                self.add_error = None;
+                self.scene.selection = Some(Selection::Model(self.scene.models.len() - 1));
+                self.renderer.add_models(&self.scene);
+            }
+            Err(load_error) => self.add_error = Some(load_error),
+        }
+    }
+    /// Returns the viewport texture, recreating the render target when its size changed.
+    fn viewport_texture(&mut self, size: [u32; 2]) -> TextureId {
+        match &mut self.viewport {
+            Some(viewport) if viewport.render_target.size == size => {}
+            Some(viewport) => {
+                viewport.render_target = self.renderer.create_render_target(size);
+                self.render_state
+                    .renderer
+                    .write()
+                    .update_egui_texture_from_wgpu_texture(
+                        &self.render_state.device,
+                        &viewport.render_target.color_texture_view,
+                        wgpu::FilterMode::Linear,
+                        viewport.texture_id,
+                    );
+            }
+            None => {
+                let render_target = self.renderer.create_render_target(size);
+                let texture_id = self.render_state.renderer.write().register_native_texture(
+                    &self.render_state.device,
+                    &render_target.color_texture_view,
+                    wgpu::FilterMode::Linear,
+                );
+                self.viewport = Some(Viewport {
+                    render_target,
+                    texture_id,
+                });
+            }
+        }
+        self.viewport.as_ref().unwrap().texture_id
     }
 }
 
-impl eframe::App for MyEguiApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        egui::SidePanel::left(egui::Id::new(1234)).show(ctx, |ui| {
-            ui.heading("Params");
-            ui.label("Position");
-            ui.add(Slider::new(&mut self.models[0].translation.x, -1.0..=1.0).text("X"));
-            ui.add(Slider::new(&mut self.models[0].translation.y, -1.0..=1.0).text("Y"));
-            ui.add(Slider::new(&mut self.models[0].translation.z, -1.0..=1.0).text("Z"));
-            ui.label("Rotation");
-            ui.add(Slider::new(&mut self.models[0].rotation.x, -PI..=PI).text("X"));
-            ui.add(Slider::new(&mut self.models[0].rotation.y, -PI..=PI).text("Y"));
-            ui.add(Slider::new(&mut self.models[0].rotation.z, -PI..=PI).text("Z"));
-            ui.label("Scaling");
-            ui.add(Slider::new(&mut self.models[0].scaling.x, -1.0..=1.0).text("X"));
-            ui.add(Slider::new(&mut self.models[0].scaling.y, -1.0..=1.0).text("Y"));
-            ui.add(Slider::new(&mut self.models[0].scaling.z, -1.0..=1.0).text("Z"));
+/// Scene edits requested by the side panel, applied once the UI is laid out.
+enum SceneAction {
+    AddModel(String),
+    /// Adds the models of a scene description file.
+    AddScene(String),
+    AddPrimitive(Primitive),
+    AddTerrain(Heights),
+    RemoveModel(usize),
+    /// Instances the model in a square grid of the given size, 1 for a single instance.
+    InstanceGrid(usize, usize),
+}
+
+/// Files with the given extension available in the models directory.
+fn model_paths(extension: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir("./models") else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .map(|path| path.to_string_lossy().into_owned())
+        .sorted()
+        .collect()
+}
+
+fn outliner_ui(ui: &mut egui::Ui, scene: &mut Scene) -> Option<SceneAction> {
+    let mut action = None;
+    ui.horizontal(|ui| {
+        ui.heading("Outliner");
+        ui.menu_button("Add model", |ui| {
+            for path in model_paths("obj") {
+                if ui.button(&path).clicked() {
+                    action = Some(SceneAction::AddModel(path));
+                    ui.close_menu();
+                }
+            }
         });
-        egui::CentralPanel::default().show(ctx, |ui| {
-            //ui.heading("Hello World!");
-            let rect = ui.clip_rect();
-            let response = ui.interact(rect.clone(), ui.id().with("drag_panel"), Sense::drag());
-            self.camera.aspect_ratio = rect.width() / rect.height();
-            if response.dragged() {
-                self.camera.rotate(
-                    -response.drag_motion().y / 100.0,
-                    -response.drag_motion().x / 100.0,
-                );
+        ui.menu_button("Add scene", |ui| {
+            for path in model_paths("scene") {
+                if ui.button(&path).clicked() {
+                    action = Some(SceneAction::AddScene(path));
+                    ui.close_menu();
+                }
+            }
+        });
+        ui.menu_button("Add primitive", |ui| {
+            for primitive in Primitive::ALL {
+                if ui.button(primitive.name()).clicked() {
+                    action = Some(SceneAction::AddPrimitive(primitive));
+                    ui.close_menu();
+                }
             }
-            let wgpu_callback = WgpuCallback::new(self.models.clone(), self.camera.clone());
-            let paint_callback = egui_wgpu::Callback::new_paint_callback(rect, wgpu_callback);
-            ui.painter().add(paint_callback);
         });
+        ui.menu_button("Add terrain", |ui| {
+            if ui.button("Noise").clicked() {
+                action = Some(SceneAction::AddTerrain(Terrain::default().heights));
+                ui.close_menu();
+            }
+            ui.label("Heightmap");
+            for path in model_paths("png") {
+                if ui.button(&path).clicked() {
+                    action = Some(SceneAction::AddTerrain(Heights::Image(path.into())));
+                    ui.close_menu();
+                }
+            }
+        });
+    });
+    for (model_idx, model) in scene.models.iter().enumerate() {
+        let id = ui.make_persistent_id(("outliner_model", model_idx));
+        egui::collapsing_header::CollapsingState::load_with_default_open(ui.ctx(), id, false)
+            .show_header(ui, |ui| {
+                let selected = scene.selection == Some(Selection::Model(model_idx));
+                if ui.selectable_label(selected, &model.name).clicked() {
+                    scene.selection = Some(Selection::Model(model_idx));
+                }
+            })
+            .body(|ui| {
+                ui.label("Meshes");
+                for (mesh_idx, _) in model.meshes.iter().enumerate() {
+                    let selection = Selection::Mesh(model_idx, mesh_idx);
+                    let text = format!("Mesh {mesh_idx}");
+                    if ui
+                        .selectable_label(scene.selection == Some(selection), text)
+                        .clicked()
+                    {
+                        scene.selection = Some(selection);
+                    }
+                }
+                ui.label("Materials");
+                for (material_idx, material) in model.materials.iter().enumerate() {
+                    let selection = Selection::Material(model_idx, material_idx);
+                    if ui
+                        .selectable_label(scene.selection == Some(selection), &material.name)
+                        .clicked()
+                    {
+                        scene.selection = Some(selection);
+                    }
+                }
+            });
     }
+    action
 }
 
-struct WgpuCallback {
-    models: Vec<Model>,
-    camera: Camera,
-}
-impl WgpuCallback {
-    fn new(models: Vec<Model>, camera: Camera) -> Self {
-        Self { models, camera }
+fn inspector_ui(ui: &mut egui::Ui, scene: &mut Scene) -> Option<SceneAction> {
+    let mut action = None;
+    ui.heading("Inspector");
+    match scene.selection {
+        None => {
+            ui.label("Nothing selected");
+        }
+        Some(Selection::Model(model_idx)) => {
+            let model = &mut scene.models[model_idx];
+            ui.text_edit_singleline(&mut model.name);
+            ui.checkbox(&mut model.visible, "Visible");
+            egui::Grid::new("transform").num_columns(4).show(ui, |ui| {
+                ui.label("Position");
+                for value in model.translation.iter_mut() {
+                    ui.add(DragValue::new(value).speed(0.01));
+                }
+                ui.end_row();
+                ui.label("Rotation");
+                for value in model.rotation.iter_mut() {
+                    ui.drag_angle(value);
+                }
+                ui.end_row();
+                ui.label("Scaling");
+                for value in model.scaling.iter_mut() {
+                    ui.add(
+                        DragValue::new(value)
+                            .speed(0.01)
+                            .range(0.001..=f32::INFINITY),
+                    );
+                }
+                ui.end_row();
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Frame").clicked() {
+                    scene.frame_selection();
+                }
+                if ui.button("Remove model").clicked() {
+                    action = Some(SceneAction::RemoveModel(model_idx));
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "Instances: {}",
+                    scene.models[model_idx].instance_count()
+                ));
+                for columns in [1, 10, 100] {
+                    if ui.button(format!("{columns}×{columns}")).clicked() {
+                        action = Some(SceneAction::InstanceGrid(model_idx, columns));
+                    }
+                }
+            });
+        }
+        Some(Selection::Mesh(model_idx, mesh_idx)) => {
+            let model = &scene.models[model_idx];
+            let mesh = &model.meshes[mesh_idx];
+            ui.label(format!("Mesh {mesh_idx} of {}", model.name));
+            ui.label(format!("Vertices: {}", mesh.positions.len() / 3));
+            ui.label(format!("Triangles: {}", mesh.indices.len() / 3));
+            for (lod_idx, lod) in model.lods[mesh_idx].iter().enumerate() {
+                ui.label(format!(
+                    "LOD {}: {} triangles, error {:.4}",
+                    lod_idx + 1,
+                    lod.indices.len() / 3,
+                    lod.error
+                ));
+            }
+            if let Some(material_idx) = mesh.material_id {
+                let material_name = &model.materials[material_idx].name;
+                if ui.link(format!("Material: {material_name}")).clicked() {
+                    scene.selection = Some(Selection::Material(model_idx, material_idx));
+                }
+            }
+        }
+        Some(Selection::Material(model_idx, material_idx)) => {
+            let material = &mut scene.models[model_idx].materials[material_idx];
+            ui.label(format!("Material {}", material.name));
+            egui::Grid::new("material").num_columns(2).show(ui, |ui| {
+                ui.label("Diffuse");
+                let mut diffuse = material.diffuse.unwrap_or([1.0; 3]);
+                if ui.color_edit_button_rgb(&mut diffuse).changed() {
+                    material.diffuse = Some(diffuse);
+                }
+                ui.end_row();
+                ui.label("Dissolve");
+                let mut dissolve = material.opacity();
+                if ui.add(Slider::new(&mut dissolve, 0.0..=1.0)).changed() {
+                    material.dissolve = Some(dissolve);
+                }
+                ui.end_row();
+                ui.label("Texture");
+                ui.label(material.diffuse_texture.as_deref().unwrap_or("None"));
+                ui.end_row();
+            });
+        }
     }
+    action
+}
+
+fn gizmo_ui(ui: &mut egui::Ui, gizmo: &mut Gizmo) {
+    ui.heading("Gizmo");
+    ui.horizontal(|ui| {
+        ui.selectable_value(&mut gizmo.mode, GizmoMode::Translate, "Translate");
+        ui.selectable_value(&mut gizmo.mode, GizmoMode::Rotate, "Rotate");
+        ui.selectable_value(&mut gizmo.mode, GizmoMode::Scale, "Scale");
+    });
+    ui.horizontal(|ui| {
+        ui.radio_value(&mut gizmo.space, GizmoSpace::World, "World");
+        ui.radio_value(&mut gizmo.space, GizmoSpace::Local, "Local");
+    });
+    let snapping = &mut gizmo.snapping;
+    ui.checkbox(&mut snapping.enabled, "Snapping");
+    ui.add_enabled_ui(snapping.enabled, |ui| {
+        egui::Grid::new("snapping").num_columns(2).show(ui, |ui| {
+            ui.label("Translation");
+            ui.add(
+                DragValue::new(&mut snapping.translation)
+                    .speed(0.01)
+                    .range(0.0..=100.0),
+            );
+            ui.end_row();
+            ui.label("Rotation");
+            ui.drag_angle(&mut snapping.rotation);
+            ui.end_row();
+            ui.label("Scaling");
+            ui.add(
+                DragValue::new(&mut snapping.scaling)
+                    .speed(0.01)
+                    .range(0.0..=10.0),
+            );
+            ui.end_row();
+        });
+    });
 }
-impl CallbackTrait for WgpuCallback {
-    fn prepare(
-        &self,
-        device: &eframe::wgpu::Device,
-        queue: &eframe::wgpu::Queue,
-        screen_descriptor: &egui_wgpu::ScreenDescriptor,
-        _egui_encoder: &mut eframe::wgpu::CommandEncoder,
-        callback_resources: &mut egui_wgpu::CallbackResources,
-    ) -> Vec<eframe::wgpu::CommandBuffer> {
-        // let renderer = Renderer::new(Cow::Borrowed(device), Cow::Borrowed(queue));
-        // let (vertex_buffer, index_buffer, bind_group, _depth_texture_view) =
-        //     renderer.create_resources(screen_descriptor.size_in_pixels, &self.models, &self.camera);
-        // callback_resources.insert(renderer.render_pipeline);
-        // callback_resources.insert((vertex_buffer, index_buffer));
-        // callback_resources.insert(bind_group);
-
-        vec![]
+
+impl eframe::App for MyEguiApp {
+    fn on_exit(&mut self) {
+        self.renderer.save_pipeline_cache();
     }
-    fn paint(
-        &self,
-        _info: egui::PaintCallbackInfo,
-        render_pass: &mut eframe::wgpu::RenderPass<'static>,
-        callback_resources: &egui_wgpu::CallbackResources,
-    ) {
-        let render_pipeline = callback_resources.get::<wgpu::RenderPipeline>().unwrap();
-        let (vertex_buffer, index_buffer) = callback_resources
-            .get::<(wgpu::Buffer, wgpu::Buffer)>()
-            .unwrap();
-        let bind_group = callback_resources.get::<wgpu::BindGroup>().unwrap();
-        render_pass.set_pipeline(&render_pipeline);
-        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-        render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-        render_pass.set_bind_group(0, bind_group, &[]);
-        render_pass.draw_indexed(0..self.models[0].meshes[0].indices.len() as u32, 0, 0..1);
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.model_watcher
+            .reload_changed(&mut self.scene, &mut self.renderer);
+        self.renderer.reload_changed_shaders();
+        // Keeps polling the files while there is no input
+        ctx.request_repaint_after(hot_reload::POLL_INTERVAL);
+        let mut actions = vec![];
+        egui::SidePanel::left(egui::Id::new(1234)).show(ctx, |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(ui.available_height() / 2.0)
+                .show(ui, |ui| {
+                    actions.extend(outliner_ui(ui, &mut self.scene));
+                });
+            if let Some(reload_error) = &self.model_watcher.last_error {
+                ui.colored_label(ui.visuals().error_fg_color, reload_error);
+            }
+            if let Some(add_error) = &self.add_error {
+                ui.colored_label(ui.visuals().error_fg_color, add_error);
+            }
+            ui.separator();
+            actions.extend(inspector_ui(ui, &mut self.scene));
+            ui.separator();
+            gizmo_ui(ui, &mut self.gizmo);
+            ui.separator();
+            ui.heading("Viewport");
+            ui.add(Slider::new(&mut self.resolution_scale, 0.25..=2.0).text("Resolution scale"));
+            ui.horizontal(|ui| {
+                ui.color_edit_button_rgba_unmultiplied(&mut self.renderer.outline.color);
+                ui.add(Slider::new(&mut self.renderer.outline.width, 0.0..=10.0).text("Outline"));
+            });
+            ComboBox::from_label("Debug view")
+                .selected_text(self.renderer.debug_view.name())
+                .show_ui(ui, |ui| {
+                    for debug_view in DebugView::ALL {
+                        ui.selectable_value(
+                            &mut self.renderer.debug_view,
+                            debug_view,
+                            debug_view.name(),
+                        );
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.renderer.grid.show_grid, "Grid");
+                ui.checkbox(&mut self.renderer.grid.show_axes, "Axes");
+                ui.checkbox(&mut self.show_bounds, "Bounds");
+            });
+            ui.horizontal(|ui| {
+                ComboBox::from_label("Culling")
+                    .selected_text(self.renderer.culling.name())
+                    .show_ui(ui, |ui| {
+                        for mode in CullingMode::ALL {
+                            ui.selectable_value(&mut self.renderer.culling, mode, mode.name());
+                        }
+                    });
+                let stats = self.renderer.culling_stats();
+                match self.renderer.effective_culling() {
+                    CullingMode::Gpu => ui.label(format!("{} on the GPU", stats.drawn)),
+                    _ => ui.label(format!(
+                        "{} drawn, {} culled, {} triangles",
+                        stats.drawn, stats.culled, stats.triangles
+                    )),
+                };
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.renderer.lod.enabled, "LOD");
+                ui.add_enabled(
+                    self.renderer.lod.enabled,
+                    Slider::new(&mut self.renderer.lod.full_detail_size, 0.05..=2.0)
+                        .logarithmic(true)
+                        .text("Full detail size"),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.renderer.normals.enabled, "Normals");
+                ui.add_enabled(
+                    self.renderer.normals.enabled,
+                    Slider::new(&mut self.renderer.normals.length, 0.001..=1.0)
+                        .logarithmic(true)
+                        .text("Length"),
+                );
+            });
+            ui.checkbox(&mut self.renderer.shaders.hot_reload, "Shader hot reload")
+                .on_hover_text("Recompile the shaders when their files in the source tree change");
+            if let Some(shader_error) = &self.renderer.shaders.last_error {
+                ui.label(
+                    egui::RichText::new(shader_error)
+                        .monospace()
+                        .color(ui.visuals().error_fg_color),
+                );
+            }
+        });
+        for action in actions {
+            match action {
+                SceneAction::AddModel(path) => {
+                    let mut model = match Model::load(
+                        &path,
+                        (Vector3::zeros(), Vector3::zeros(), Vector3::repeat(1.0)),
+                    ) {
+                        Ok(model) => model,
+                        Err(load_error) => {
+                            self.add_error = Some(load_error);
+                            continue;
+                        }
+                    };
+                    model.fit_to_size(1.0);
+                    self.add_model(model);
+                }
+                SceneAction::AddScene(path) => {
+                    self.add_error = self.scene.add_scene_file(&path).err();
+                    self.renderer.add_models(&self.scene);
+                }
+                SceneAction::AddPrimitive(primitive) => {
+                    self.add_model(Model::primitive(
+                        primitive,
+                        (Vector3::zeros(), Vector3::zeros(), Vector3::repeat(1.0)),
+                    ));
+                }
+                SceneAction::AddTerrain(heights) => {
+                    let terrain = Terrain {
+                        heights,
+                        ..Default::default()
+                    };
+                    match terrain.model() {
+                        Ok(model) => self.add_model(model),
+                        Err(load_error) => self.add_error = Some(load_error),
+                    }
+                }
+                SceneAction::RemoveModel(model_idx) => {
+                    self.scene.remove_model(model_idx);
+                    self.renderer.remove_model(&self.scene, model_idx);
+                }
+                SceneAction::InstanceGrid(model_idx, columns) => {
+                    self.scene.models[model_idx].instance_grid(columns, columns);
+                    self.renderer.refresh_scene(&self.scene);
+                }
+            }
+        }
+        egui::CentralPanel::default()
+            .frame(egui::Frame::NONE)
+            .show(ctx, |ui| {
+                let rect_size = ui.available_size();
+                let scale = ctx.pixels_per_point() * self.resolution_scale;
+                let size = [
+                    ((rect_size.x * scale).round() as u32).clamp(1, 8192),
+                    ((rect_size.y * scale).round() as u32).clamp(1, 8192),
+                ];
+                self.scene.camera.aspect_ratio = rect_size.x / rect_size.y;
+
+                let texture_id = self.viewport_texture(size);
+                let response = ui
+                    .add(egui::Image::new((texture_id, rect_size)).sense(Sense::click_and_drag()));
+                let camera = &self.scene.camera;
+                let ray_at = |pointer_position: egui::Pos2| {
+                    let position = pointer_position - response.rect.min;
+                    Ray::from_viewport(camera, [position.x, position.y], [rect_size.x, rect_size.y])
+                };
+                let selected_model = self
+                    .scene
+                    .selection
+                    .map(|selection| &mut self.scene.models[selection.model_idx()]);
+
+                if let Some(model) = selected_model {
+                    if response.drag_started()
+                        && let Some(pointer_position) = response.interact_pointer_pos()
+                    {
+                        self.gizmo
+                            .begin_drag(model, camera, &ray_at(pointer_position));
+                    } else if self.gizmo.is_dragging()
+                        && let Some(pointer_position) = response.interact_pointer_pos()
+                    {
+                        self.gizmo.drag(model, &ray_at(pointer_position));
+                    } else if let Some(hover_position) = response.hover_pos() {
+                        self.gizmo.hover(model, camera, &ray_at(hover_position));
+                    }
+                }
+                if response.drag_stopped() {
+                    self.gizmo.end_drag();
+                }
+                if response.clicked()
+                    && let Some(pointer_position) = response.interact_pointer_pos()
+                {
+                    let hit = picking::pick(&self.scene, &ray_at(pointer_position));
+                    if let Some(hit) = &hit {
+                        info!("Picked {hit:?}");
+                    }
+                    self.scene.selection = hit.map(|hit| Selection::Model(hit.model_idx));
+                }
+                if response.dragged() && !self.gizmo.is_dragging() {
+                    self.scene.camera.rotate(
+                        -response.drag_motion().y / 100.0,
+                        -response.drag_motion().x / 100.0,
+                    );
+                    ctx.request_repaint();
+                }
+
+                let gizmo_lines = match self.scene.selection {
+                    Some(selection) => self.gizmo.lines(
+                        &self.scene.models[selection.model_idx()],
+                        &self.scene.camera,
+                    ),
+                    None => vec![],
+                };
+                self.renderer.set_overlay_lines(&gizmo_lines);
+                if self.show_bounds {
+                    for model in self.scene.models.iter().filter(|model| model.visible) {
+                        self.renderer
+                            .debug
+                            .depth_tested
+                            .bounds(&model.world_bounds(), BOUNDS_COLOR);
+                    }
+                }
+                let viewport = self.viewport.as_ref().unwrap();
+                self.renderer
+                    .render_to_target(&viewport.render_target, &self.scene);
+            });
     }
 }
@@ -0,0 +1,372 @@
+use std::f32::consts::TAU;
+
+use nalgebra::{Point3, Unit, UnitQuaternion, Vector3};
+
+use crate::{
+    models::{Camera, Model},
+    picking::Ray,
+    renderer::{AXIS_COLORS, LineVertex},
+};
+
+const ACTIVE_COLOR: [f32; 4] = [1.0, 0.9, 0.1, 1.0];
+/// Gizmo size relative to its distance to the camera, so that it keeps the same size on screen.
+const SCREEN_SCALE: f32 = 0.2;
+/// Distance under which a handle is hovered, relative to the gizmo size.
+const PICK_TOLERANCE: f32 = 0.06;
+/// Extent of the plane handles along their two axes, relative to the gizmo size.
+const PLANE_HANDLE_EXTENT: (f32, f32) = (0.25, 0.45);
+const RING_SEGMENTS: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GizmoMode {
+    Translate,
+    Rotate,
+    Scale,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GizmoSpace {
+    World,
+    Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GizmoHandle {
+    Axis(usize),
+    /// Plane perpendicular to the given axis
+    Plane(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Snapping {
+    pub enabled: bool,
+    pub translation: f32,
+    /// In radians
+    pub rotation: f32,
+    pub scaling: f32,
+}
+impl Snapping {
+    fn snap(&self, value: f32, increment: f32) -> f32 {
+        if self.enabled && increment > 0.0 {
+            (value / increment).round() * increment
+        } else {
+            value
+        }
+    }
+}
+
+/// Position, orientation and size of the gizmo in world space.
+#[derive(Debug, Clone, Copy)]
+struct GizmoFrame {
+    origin: Point3<f32>,
+    axes: [Vector3<f32>; 3],
+    size: f32,
+}
+impl GizmoFrame {
+    /// The two axes spanning the plane perpendicular to `axis`.
+    fn plane_axes(&self, axis: usize) -> (Vector3<f32>, Vector3<f32>) {
+        (self.axes[(axis + 1) % 3], self.axes[(axis + 2) % 3])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GizmoDrag {
+    handle: GizmoHandle,
+    frame: GizmoFrame,
+    grab_point: Point3<f32>,
+    translation: Vector3<f32>,
+    rotation: Vector3<f32>,
+    scaling: Vector3<f32>,
+}
+
+/// Interactive translate/rotate/scale handles for the selected model.
+#[derive(Debug, Clone)]
+pub struct Gizmo {
+    pub mode: GizmoMode,
+    pub space: GizmoSpace,
+    pub snapping: Snapping,
+    pub hovered: Option<GizmoHandle>,
+    drag: Option<GizmoDrag>,
+}
+impl Default for Gizmo {
+    fn default() -> Self {
+        Self {
+            mode: GizmoMode::Translate,
+            space: GizmoSpace::World,
+            snapping: Snapping {
+                enabled: false,
+                translation: 0.1,
+                rotation: 15f32.to_radians(),
+                scaling: 0.1,
+            },
+            hovered: None,
+            drag: None,
+        }
+    }
+}
+impl Gizmo {
+    pub fn is_dragging(&self) -> bool {
+        self.drag.is_some()
+    }
+    pub fn toggle_space(&mut self) {
+        self.space = match self.space {
+            GizmoSpace::World => GizmoSpace::Local,
+            GizmoSpace::Local => GizmoSpace::World,
+        };
+    }
+    fn frame(&self, model: &Model, camera: &Camera) -> GizmoFrame {
+        let origin = Point3::from(model.translation);
+        // Scaling happens before rotation, so it is always along the local axes
+        let axes = if self.space == GizmoSpace::Local || self.mode == GizmoMode::Scale {
+            let rotation = UnitQuaternion::from_scaled_axis(model.rotation);
+            [0, 1, 2].map(|axis| rotation * Vector3::ith(axis, 1.0))
+        } else {
+            [0, 1, 2].map(|axis| Vector3::ith(axis, 1.0))
+        };
+        GizmoFrame {
+            origin,
+            axes,
+            size: (camera.eye() - origin).norm() * SCREEN_SCALE,
+        }
+    }
+    /// Closest handle under the ray.
+    fn hit(&self, frame: &GizmoFrame, ray: &Ray) -> Option<GizmoHandle> {
+        let tolerance = PICK_TOLERANCE * frame.size;
+        let mut closest: Option<(GizmoHandle, f32)> = None;
+        let mut consider = |handle, distance: f32| {
+            if closest.is_none_or(|(_, closest_distance)| distance < closest_distance) {
+                closest = Some((handle, distance));
+            }
+        };
+        for axis in 0..3 {
+            match self.mode {
+                GizmoMode::Translate | GizmoMode::Scale => {
+                    let (t, s) = closest_to_line(ray, &frame.origin, &frame.axes[axis]);
+                    let s = s.clamp(0.0, frame.size);
+                    let axis_point = frame.origin + frame.axes[axis] * s;
+                    if t > 0.0 && (ray.at(t) - axis_point).norm() < tolerance {
+                        consider(GizmoHandle::Axis(axis), t);
+                    }
+                    if let Some(t) = intersect_plane(ray, &frame.origin, &frame.axes[axis]) {
+                        let offset = ray.at(t) - frame.origin;
+                        let (u_axis, v_axis) = frame.plane_axes(axis);
+                        let (min, max) = PLANE_HANDLE_EXTENT;
+                        let range = min * frame.size..=max * frame.size;
+                        if range.contains(&offset.dot(&u_axis))
+                            && range.contains(&offset.dot(&v_axis))
+                        {
+                            consider(GizmoHandle::Plane(axis), t);
+                        }
+                    }
+                }
+                GizmoMode::Rotate => {
+                    if let Some(t) = intersect_plane(ray, &frame.origin, &frame.axes[axis]) {
+                        let radius = (ray.at(t) - frame.origin).norm();
+                        if (radius - frame.size).abs() < tolerance {
+                            consider(GizmoHandle::Axis(axis), t);
+                        }
+                    }
+                }
+            }
+        }
+        closest.map(|(handle, _)| handle)
+    }
+    /// Point under the ray on the line or plane the handle moves along.
+    fn grab_point(
+        &self,
+        handle: GizmoHandle,
+        frame: &GizmoFrame,
+        ray: &Ray,
+    ) -> Option<Point3<f32>> {
+        match (self.mode, handle) {
+            (GizmoMode::Translate | GizmoMode::Scale, GizmoHandle::Axis(axis)) => {
+                let (_, s) = closest_to_line(ray, &frame.origin, &frame.axes[axis]);
+                s.is_finite().then(|| frame.origin + frame.axes[axis] * s)
+            }
+            (_, GizmoHandle::Plane(axis)) | (GizmoMode::Rotate, GizmoHandle::Axis(axis)) => {
+                intersect_plane(ray, &frame.origin, &frame.axes[axis]).map(|t| ray.at(t))
+            }
+        }
+    }
+    /// Updates the hovered handle, returns whether it changed.
+    pub fn hover(&mut self, model: &Model, camera: &Camera, ray: &Ray) -> bool {
+        if self.is_dragging() {
+            return false;
+        }
+        let hovered = self.hit(&self.frame(model, camera), ray);
+        let changed = hovered != self.hovered;
+        self.hovered = hovered;
+        changed
+    }
+    /// Starts dragging the handle under the ray, returns false when there is none.
+    pub fn begin_drag(&mut self, model: &Model, camera: &Camera, ray: &Ray) -> bool {
+        let frame = self.frame(model, camera);
+        let Some(handle) = self.hit(&frame, ray) else {
+            return false;
+        };
+        let Some(grab_point) = self.grab_point(handle, &frame, ray) else {
+            return false;
+        };
+        self.drag = Some(GizmoDrag {
+            handle,
+            frame,
+            grab_point,
+            translation: model.translation,
+            rotation: model.rotation,
+            scaling: model.scaling,
+        });
+        true
+    }
+    pub fn end_drag(&mut self) {
+        self.drag = None;
+    }
+    /// Applies the ongoing drag to the model's transform.
+    pub fn drag(&self, model: &mut Model, ray: &Ray) {
+        let Some(drag) = &self.drag else {
+            return;
+        };
+        let Some(point) = self.grab_point(drag.handle, &drag.frame, ray) else {
+            return;
+        };
+        let frame = &drag.frame;
+        let snapping = &self.snapping;
+        match (self.mode, drag.handle) {
+            (GizmoMode::Translate, _) => {
+                let delta = point - drag.grab_point;
+                let snapped_delta: Vector3<f32> = frame
+                    .axes
+                    .iter()
+                    .map(|axis| axis * snapping.snap(delta.dot(axis), snapping.translation))
+                    .sum();
+                model.translation = drag.translation + snapped_delta;
+            }
+            (GizmoMode::Rotate, GizmoHandle::Axis(axis)) => {
+                let from = drag.grab_point - frame.origin;
+                let to = point - frame.origin;
+                let angle = frame.axes[axis].dot(&from.cross(&to)).atan2(from.dot(&to));
+                let angle = snapping.snap(angle, snapping.rotation);
+                let delta =
+                    UnitQuaternion::from_axis_angle(&Unit::new_normalize(frame.axes[axis]), angle);
+                model.rotation =
+                    (delta * UnitQuaternion::from_scaled_axis(drag.rotation)).scaled_axis();
+            }
+            (GizmoMode::Rotate, GizmoHandle::Plane(_)) => {}
+            (GizmoMode::Scale, handle) => {
+                let from = drag.grab_point - frame.origin;
+                let to = point - frame.origin;
+                let (factor, scaled_axes) = match handle {
+                    GizmoHandle::Axis(axis) => {
+                        let direction = frame.axes[axis];
+                        (to.dot(&direction) / from.dot(&direction), vec![axis])
+                    }
+                    GizmoHandle::Plane(axis) => (
+                        to.norm() / from.norm(),
+                        vec![(axis + 1) % 3, (axis + 2) % 3],
+                    ),
+                };
+                if !factor.is_finite() {
+                    return;
+                }
+                let factor = snapping.snap(factor, snapping.scaling);
+                for axis in scaled_axes {
+                    model.scaling[axis] = (drag.scaling[axis] * factor).max(0.001);
+                }
+            }
+        }
+    }
+    /// Line segments of the gizmo around the model.
+    pub fn lines(&self, model: &Model, camera: &Camera) -> Vec<LineVertex> {
+        let frame = match &self.drag {
+            // Keep the orientation of the drag start, but follow the model
+            Some(drag) => GizmoFrame {
+                origin: Point3::from(model.translation),
+                ..drag.frame
+            },
+            None => self.frame(model, camera),
+        };
+        let active = self.drag.map(|drag| drag.handle).or(self.hovered);
+        let color = |handle: GizmoHandle| match handle {
+            _ if active == Some(handle) => ACTIVE_COLOR,
+            GizmoHandle::Axis(axis) | GizmoHandle::Plane(axis) => AXIS_COLORS[axis],
+        };
+        let mut vertices = vec![];
+        let mut line = |a: Point3<f32>, b: Point3<f32>, color: [f32; 4]| {
+            vertices.push(LineVertex {
+                position: a.into(),
+                color,
+            });
+            vertices.push(LineVertex {
+                position: b.into(),
+                color,
+            });
+        };
+        let origin = frame.origin;
+        let size = frame.size;
+        for axis in 0..3 {
+            let direction = frame.axes[axis];
+            let (u_axis, v_axis) = frame.plane_axes(axis);
+            match self.mode {
+                GizmoMode::Translate | GizmoMode::Scale => {
+                    let axis_color = color(GizmoHandle::Axis(axis));
+                    let tip = origin + direction * size;
+                    line(origin, tip, axis_color);
+                    if self.mode == GizmoMode::Translate {
+                        // Arrow head
+                        let base = tip - direction * size * 0.15;
+                        for side in [u_axis, -u_axis, v_axis, -v_axis] {
+                            line(tip, base + side * size * 0.05, axis_color);
+                        }
+                    } else {
+                        // Box at the end of the axis
+                        let half = size * 0.04;
+                        let corners = [(1.0, 1.0), (-1.0, 1.0), (-1.0, -1.0), (1.0, -1.0)]
+                            .map(|(u, v)| tip + (u_axis * u + v_axis * v) * half);
+                        for i in 0..4 {
+                            line(corners[i], corners[(i + 1) % 4], axis_color);
+                        }
+                    }
+                    let (min, max) = PLANE_HANDLE_EXTENT;
+                    let corners = [(min, min), (max, min), (max, max), (min, max)]
+                        .map(|(u, v)| origin + (u_axis * u + v_axis * v) * size);
+                    let plane_color = color(GizmoHandle::Plane(axis));
+                    for i in 0..4 {
+                        line(corners[i], corners[(i + 1) % 4], plane_color);
+                    }
+                }
+                GizmoMode::Rotate => {
+                    let ring_color = color(GizmoHandle::Axis(axis));
+                    let point = |i: usize| {
+                        let angle = i as f32 * TAU / RING_SEGMENTS as f32;
+                        origin + (u_axis * angle.cos() + v_axis * angle.sin()) * size
+                    };
+                    for i in 0..RING_SEGMENTS {
+                        line(point(i), point(i + 1), ring_color);
+                    }
+                }
+            }
+        }
+        vertices
+    }
+}
+
+/// Ray parameter and line parameter of the closest points between a ray and an infinite line.
+fn closest_to_line(ray: &Ray, point: &Point3<f32>, direction: &Vector3<f32>) -> (f32, f32) {
+    let w = ray.origin - point;
+    let a = ray.direction.dot(&ray.direction);
+    let b = ray.direction.dot(direction);
+    let c = direction.dot(direction);
+    let d = ray.direction.dot(&w);
+    let e = direction.dot(&w);
+    let denominator = a * c - b * b;
+    ((b * e - c * d) / denominator, (a * e - b * d) / denominator)
+}
+
+/// Ray parameter of the intersection with a plane, if in front of the ray.
+fn intersect_plane(ray: &Ray, point: &Point3<f32>, normal: &Vector3<f32>) -> Option<f32> {
+    let denominator = normal.dot(&ray.direction);
+    if denominator.abs() < 1e-6 {
+        return None;
+    }
+    let t = normal.dot(&(point - ray.origin)) / denominator;
+    (t > 0.0).then_some(t)
+}
@@ -0,0 +1,97 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use itertools::Itertools;
+use log::{error, info};
+
+use crate::{renderer::Renderer, scene::Scene};
+
+/// How often the files are checked, often enough for files saved from another application.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tells when files change from their modification times, polled at most every `POLL_INTERVAL`.
+#[derive(Default)]
+pub struct FilePoller {
+    modified: HashMap<PathBuf, Option<SystemTime>>,
+    last_poll: Option<Instant>,
+}
+impl FilePoller {
+    /// Whether it is time to poll the files again, which then counts as polled.
+    pub fn is_due(&mut self) -> bool {
+        if self
+            .last_poll
+            .is_some_and(|last_poll| last_poll.elapsed() < POLL_INTERVAL)
+        {
+            return false;
+        }
+        self.last_poll = Some(Instant::now());
+        true
+    }
+    /// Whether the file was modified, created or deleted since it was last checked. Files checked
+    /// for the first time are only recorded.
+    pub fn changed(&mut self, path: PathBuf) -> bool {
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        self.modified
+            .insert(path, modified)
+            .is_some_and(|previous| previous != modified)
+    }
+}
+
+/// Reloads the models of the scene when the files they were loaded from change.
+#[derive(Default)]
+pub struct ModelWatcher {
+    poller: FilePoller,
+    /// Of the last reload, until a reload succeeds.
+    pub last_error: Option<String>,
+}
+impl ModelWatcher {
+    /// Models with a file that changed since the last poll.
+    pub fn poll(&mut self, scene: &Scene) -> Vec<usize> {
+        if !self.poller.is_due() {
+            return vec![];
+        }
+        let model_files = (0..scene.models.len())
+            .map(|model_idx| scene.model_files(model_idx))
+            .collect_vec();
+        // Each file is checked once, so that all the models sharing it see it change
+        let changed_files: HashSet<&PathBuf> = model_files
+            .iter()
+            .flatten()
+            .unique()
+            .filter(|path| self.poller.changed(path.to_path_buf()))
+            .collect();
+        model_files
+            .iter()
+            .positions(|files| files.iter().any(|path| changed_files.contains(path)))
+            .collect()
+    }
+    /// Reloads the models whose files changed in the scene and on the GPU. Models that fail to
+    /// load stay as they were, until their files change again. Returns whether any was reloaded.
+    pub fn reload_changed(&mut self, scene: &mut Scene, renderer: &mut Renderer) -> bool {
+        let changed_models = self.poll(scene);
+        for &model_idx in &changed_models {
+            match scene.reload_model(model_idx) {
+                Ok(()) => {
+                    info!("Reloaded {}", scene.models[model_idx].name);
+                    renderer.reload_model(scene, model_idx);
+                    self.last_error = None;
+                }
+                Err(reload_error) => {
+                    let message = format!(
+                        "Reloading {} failed: {reload_error}",
+                        scene.models[model_idx].name
+                    );
+                    error!("{message}");
+                    self.last_error = Some(message);
+                }
+            }
+        }
+        !changed_models.is_empty()
+    }
+}
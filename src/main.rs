@@ -4,9 +4,19 @@
 use log::info;
 use std::{panic, process};
 
+mod bounds;
 mod egui_app;
+mod gizmo;
+mod hot_reload;
+mod mesh_optimizer;
+mod mesh_simplifier;
 mod models;
+mod picking;
+mod primitives;
 mod renderer;
+mod scene;
+mod scene_file;
+mod terrain;
 mod winit_app;
 
 fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use tobj::Mesh;
+
+/// Size of the post-transform vertex cache the triangles are ordered for, a common one on
+/// current GPUs.
+const CACHE_SIZE: u32 = 16;
+
+/// Rewrites the vertices and indices of a triangulated, single-indexed mesh for the GPU:
+/// identical vertices are merged, triangles reordered to reuse recently transformed vertices,
+/// and vertices reordered by first use so that fetching them reads memory linearly. What the
+/// mesh looks like doesn't change.
+pub fn optimize_mesh(mesh: &mut Mesh) {
+    deduplicate_vertices(mesh);
+    mesh.indices = optimize_vertex_cache(&mesh.indices, vertex_count(mesh));
+    optimize_vertex_fetch(mesh);
+}
+
+fn vertex_count(mesh: &Mesh) -> usize {
+    mesh.positions.len() / 3
+}
+
+/// Per-vertex attributes of the mesh that are present, with their number of components.
+fn attributes(mesh: &mut Mesh) -> impl Iterator<Item = (&mut Vec<f32>, usize)> {
+    [
+        (&mut mesh.positions, 3),
+        (&mut mesh.normals, 3),
+        (&mut mesh.texcoords, 2),
+        (&mut mesh.vertex_color, 3),
+    ]
+    .into_iter()
+    .filter(|(values, _)| !values.is_empty())
+}
+
+/// Keeps the vertices at the given old indices, in that order.
+fn reorder_vertices(mesh: &mut Mesh, order: &[u32]) {
+    for (values, components) in attributes(mesh) {
+        *values = order
+            .iter()
+            .flat_map(|&vertex| {
+                let start = vertex as usize * components;
+                values[start..start + components].iter().copied()
+            })
+            .collect();
+    }
+}
+
+/// Merges vertices whose attributes are bitwise identical, which the OBJ loader keeps apart when
+/// they come from distinct position, normal or texture coordinate indices.
+fn deduplicate_vertices(mesh: &mut Mesh) {
+    let vertex_count = vertex_count(mesh);
+    let mut keys = vec![vec![]; vertex_count];
+    for (values, components) in attributes(mesh) {
+        for (key, vertex_values) in keys.iter_mut().zip(values.chunks_exact(components)) {
+            key.extend(vertex_values.iter().map(|value| value.to_bits()));
+        }
+    }
+    let mut unique_vertices = HashMap::new();
+    let mut order = vec![];
+    let remap = keys
+        .into_iter()
+        .enumerate()
+        .map(|(vertex, key)| {
+            *unique_vertices.entry(key).or_insert_with(|| {
+                order.push(vertex as u32);
+                order.len() as u32 - 1
+            })
+        })
+        .collect::<Vec<_>>();
+    if order.len() == vertex_count {
+        return;
+    }
+    reorder_vertices(mesh, &order);
+    for index in &mut mesh.indices {
+        *index = remap[*index as usize];
+    }
+}
+
+/// Orders the triangles for the post-transform vertex cache, following Tipsify (Sander, Nehab
+/// and Barczak, "Fast Triangle Reordering for Vertex Locality and Reduced Overdraw"): triangles
+/// are emitted in fans around a vertex, moving on to the neighbor that is still in the cache and
+/// has the fewest triangles left.
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    // Triangles using each vertex, as ranges of a single array
+    let mut live_triangles = vec![0u32; vertex_count];
+    for &vertex in indices {
+        live_triangles[vertex as usize] += 1;
+    }
+    let mut offsets = vec![0; vertex_count + 1];
+    for vertex in 0..vertex_count {
+        offsets[vertex + 1] = offsets[vertex] + live_triangles[vertex] as usize;
+    }
+    let mut adjacency = vec![0; indices.len()];
+    let mut filled = offsets.clone();
+    for (triangle, vertices) in indices.chunks_exact(3).enumerate() {
+        for &vertex in vertices {
+            adjacency[filled[vertex as usize]] = triangle;
+            filled[vertex as usize] += 1;
+        }
+    }
+
+    let mut cache_times = vec![0; vertex_count];
+    let mut emitted = vec![false; triangle_count];
+    let mut dead_end = vec![];
+    let mut output = Vec::with_capacity(indices.len());
+    let mut time = CACHE_SIZE + 1;
+    let mut cursor = 0;
+    let mut fanning = next_live_vertex(&live_triangles, &mut dead_end, &mut cursor);
+    while let Some(vertex) = fanning {
+        let mut candidates = vec![];
+        for &triangle in &adjacency[offsets[vertex]..offsets[vertex + 1]] {
+            if emitted[triangle] {
+                continue;
+            }
+            emitted[triangle] = true;
+            for &neighbor in &indices[triangle * 3..triangle * 3 + 3] {
+                output.push(neighbor);
+                dead_end.push(neighbor);
+                candidates.push(neighbor);
+                live_triangles[neighbor as usize] -= 1;
+                if time - cache_times[neighbor as usize] > CACHE_SIZE {
+                    cache_times[neighbor as usize] = time;
+                    time += 1;
+                }
+            }
+        }
+        // Prefer the candidate that entered the cache the earliest, as long as fanning around
+        // it won't push it out
+        fanning = candidates
+            .iter()
+            .filter(|&&candidate| live_triangles[candidate as usize] > 0)
+            .max_by_key(|&&candidate| {
+                let age = time - cache_times[candidate as usize];
+                if age + 2 * live_triangles[candidate as usize] <= CACHE_SIZE {
+                    age
+                } else {
+                    0
+                }
+            })
+            .map(|&candidate| candidate as usize)
+            .or_else(|| next_live_vertex(&live_triangles, &mut dead_end, &mut cursor));
+    }
+    output
+}
+
+/// Any vertex with triangles left, the most recently used ones first.
+fn next_live_vertex(
+    live_triangles: &[u32],
+    dead_end: &mut Vec<u32>,
+    cursor: &mut usize,
+) -> Option<usize> {
+    while let Some(vertex) = dead_end.pop() {
+        if live_triangles[vertex as usize] > 0 {
+            return Some(vertex as usize);
+        }
+    }
+    while *cursor < live_triangles.len() {
+        if live_triangles[*cursor] > 0 {
+            return Some(*cursor);
+        }
+        *cursor += 1;
+    }
+    None
+}
+
+/// Numbers the vertices in the order the triangles first use them, dropping unused ones.
+fn optimize_vertex_fetch(mesh: &mut Mesh) {
+    let mut remap = vec![u32::MAX; vertex_count(mesh)];
+    let mut order = vec![];
+    for index in &mut mesh.indices {
+        let new_index = &mut remap[*index as usize];
+        if *new_index == u32::MAX {
+            *new_index = order.len() as u32;
+            order.push(*index);
+        }
+        *index = *new_index;
+    }
+    reorder_vertices(mesh, &order);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Triangles as the positions of their vertices, sorted, to compare meshes whatever their
+    /// vertex and triangle orders.
+    fn triangles(mesh: &Mesh) -> Vec<[[u32; 3]; 3]> {
+        let position = |index: u32| {
+            let start = index as usize * 3;
+            [0, 1, 2].map(|axis| mesh.positions[start + axis].to_bits())
+        };
+        let mut triangles = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|triangle| [0, 1, 2].map(|corner| position(triangle[corner])))
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    /// Cube with each triangle having vertices of its own, like the OBJ loader loads it.
+    fn unshared_cube() -> Mesh {
+        let corner = |idx: u32| [idx & 1, (idx >> 1) & 1, (idx >> 2) & 1].map(|bit| bit as f32);
+        let faces = [
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+        ];
+        let positions = faces
+            .iter()
+            .flat_map(|&[a, b, c, d]| [a, b, c, a, c, d])
+            .flat_map(corner)
+            .collect::<Vec<_>>();
+        Mesh {
+            indices: (0..positions.len() as u32 / 3).collect(),
+            positions,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merges_shared_corners() {
+        let mut mesh = unshared_cube();
+        let original_triangles = triangles(&mesh);
+        assert_eq!(vertex_count(&mesh), 36);
+        optimize_mesh(&mut mesh);
+        assert_eq!(vertex_count(&mesh), 8);
+        assert_eq!(triangles(&mesh), original_triangles);
+        // Numbered in the order of first use
+        let mut first_uses = vec![];
+        for &index in &mesh.indices {
+            if !first_uses.contains(&index) {
+                first_uses.push(index);
+            }
+        }
+        assert_eq!(first_uses, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reorders_triangles_without_changing_them() {
+        // Grid of 10×10 quads, with vertices in rows of 11
+        let indices = (0..10)
+            .flat_map(|row| (0..10).map(move |column| row * 11 + column))
+            .flat_map(|vertex| {
+                [
+                    vertex,
+                    vertex + 11,
+                    vertex + 1,
+                    vertex + 1,
+                    vertex + 11,
+                    vertex + 12,
+                ]
+            })
+            .collect::<Vec<u32>>();
+        let optimized = optimize_vertex_cache(&indices, 121);
+        let sorted_triangles = |indices: &[u32]| {
+            let mut triangles = indices
+                .chunks_exact(3)
+                .map(<[u32]>::to_vec)
+                .collect::<Vec<_>>();
+            triangles.sort();
+            triangles
+        };
+        assert_eq!(sorted_triangles(&optimized), sorted_triangles(&indices));
+    }
+
+    #[test]
+    fn teapot_vertices_are_already_unique() {
+        let (models, _) = tobj::load_obj(
+            "./models/teapot.obj",
+            &tobj::LoadOptions {
+                single_index: true,
+                triangulate: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut meshes = models
+            .into_iter()
+            .map(|model| model.mesh)
+            .collect::<Vec<_>>();
+        let vertices = |meshes: &[Mesh]| meshes.iter().map(vertex_count).sum::<usize>();
+        let loaded_vertices = vertices(&meshes);
+        for mesh in &mut meshes {
+            optimize_mesh(mesh);
+        }
+        // The loader merges the corners with the same position and texture coordinate indices,
+        // which leaves no duplicate in the teapot: it only shrinks by its 16-bit indices
+        assert_eq!(loaded_vertices, 8334);
+        assert_eq!(vertices(&meshes), 8334);
+    }
+}
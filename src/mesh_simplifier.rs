@@ -0,0 +1,354 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    ops::{Add, AddAssign},
+};
+
+use nalgebra::Vector3;
+
+use crate::mesh_optimizer;
+
+/// Coarser levels than the mesh itself, at most.
+pub const MAX_LOD_LEVELS: usize = 5;
+/// Triangles of each level, relative to the previous one.
+const LOD_TRIANGLE_RATIO: f32 = 0.5;
+/// Meshes with fewer triangles than that aren't worth simplifying any further.
+const MIN_LOD_TRIANGLES: usize = 64;
+
+/// A simplified version of a mesh, drawing a subset of its vertices with fewer triangles.
+#[derive(Debug, Clone)]
+pub struct Lod {
+    pub indices: Vec<u32>,
+    /// Estimate of how far the surface moved from the mesh's, in model space.
+    pub error: f32,
+}
+
+/// Coarser and coarser versions of a mesh, each with about half the triangles of the previous
+/// one, stopping when the simplification can't keep up.
+pub fn lod_chain(positions: &[f32], indices: &[u32]) -> Vec<Lod> {
+    let vertex_count = positions.len() / 3;
+    let mut lods: Vec<Lod> = vec![];
+    for _ in 0..MAX_LOD_LEVELS {
+        let (previous_indices, previous_error) = lods
+            .last()
+            .map_or((indices, 0.0), |lod| (&lod.indices[..], lod.error));
+        let triangle_count = previous_indices.len() / 3;
+        if triangle_count < MIN_LOD_TRIANGLES * 2 {
+            break;
+        }
+        let target_triangle_count = (triangle_count as f32 * LOD_TRIANGLE_RATIO) as usize;
+        let (simplified, error) = simplify(positions, previous_indices, target_triangle_count);
+        // Borders and seams stay where they are, which can leave little to simplify
+        if simplified.len() as f32 > previous_indices.len() as f32 * 0.8 {
+            break;
+        }
+        lods.push(Lod {
+            indices: mesh_optimizer::optimize_vertex_cache(&simplified, vertex_count),
+            error: previous_error + error,
+        });
+    }
+    lods
+}
+
+/// Symmetric 4×4 matrix summing the squared distances to a set of planes, each weighted by the
+/// area of its triangle.
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric {
+    coefficients: [f64; 10],
+    weight: f64,
+}
+impl Quadric {
+    fn from_plane(normal: Vector3<f64>, distance: f64, weight: f64) -> Self {
+        let [a, b, c] = [normal.x, normal.y, normal.z];
+        let d = distance;
+        Self {
+            coefficients: [
+                a * a,
+                a * b,
+                a * c,
+                a * d,
+                b * b,
+                b * c,
+                b * d,
+                c * c,
+                c * d,
+                d * d,
+            ]
+            .map(|q| q * weight),
+            weight,
+        }
+    }
+    fn error(&self, point: &Vector3<f64>) -> f64 {
+        let [a2, ab, ac, ad, b2, bc, bd, c2, cd, d2] = self.coefficients;
+        let [x, y, z] = [point.x, point.y, point.z];
+        x * x * a2
+            + 2.0 * x * y * ab
+            + 2.0 * x * z * ac
+            + 2.0 * x * ad
+            + y * y * b2
+            + 2.0 * y * z * bc
+            + 2.0 * y * bd
+            + z * z * c2
+            + 2.0 * z * cd
+            + d2
+    }
+    /// Root mean square distance to the planes.
+    fn distance(&self, point: &Vector3<f64>) -> f64 {
+        if self.weight > 0.0 {
+            (self.error(point) / self.weight).max(0.0).sqrt()
+        } else {
+            0.0
+        }
+    }
+}
+impl Add for Quadric {
+    type Output = Quadric;
+    fn add(mut self, other: Quadric) -> Quadric {
+        self += other;
+        self
+    }
+}
+impl AddAssign for Quadric {
+    fn add_assign(&mut self, other: Quadric) {
+        for (q, other_q) in self.coefficients.iter_mut().zip(other.coefficients) {
+            *q += other_q;
+        }
+        self.weight += other.weight;
+    }
+}
+
+/// Moving vertex `from` onto vertex `to`, valid as long as neither changed since it was costed.
+#[derive(Debug, Clone, Copy)]
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+    from_version: u32,
+    to_version: u32,
+}
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Collapse {}
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Collapse {
+    /// Reversed, for the heap to pop the cheapest collapse first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+/// Simplifies a triangle list down to about `target_triangle_count` triangles by quadric error
+/// metrics (Garland and Heckbert, "Surface Simplification Using Quadric Error Metrics"), always
+/// collapsing an edge onto one of its vertices so that the result indexes the same vertices.
+/// Vertices on an edge of a single triangle don't move: in a single-indexed mesh these are both
+/// the borders and the seams where normals or texture coordinates are split. Returns the
+/// indices and the largest distance a collapse moved the surface by.
+pub fn simplify(
+    positions: &[f32],
+    indices: &[u32],
+    target_triangle_count: usize,
+) -> (Vec<u32>, f32) {
+    let vertex_count = positions.len() / 3;
+    let position = |vertex: u32| {
+        let start = vertex as usize * 3;
+        Vector3::new(
+            positions[start] as f64,
+            positions[start + 1] as f64,
+            positions[start + 2] as f64,
+        )
+    };
+    let mut triangles = indices
+        .chunks_exact(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect::<Vec<_>>();
+    let normal = |triangle: &[u32; 3]| {
+        let [a, b, c] = triangle.map(position);
+        (b - a).cross(&(c - a))
+    };
+
+    let mut quadrics = vec![Quadric::default(); vertex_count];
+    let mut vertex_triangles = vec![vec![]; vertex_count];
+    let mut edge_uses = std::collections::HashMap::new();
+    for (triangle_idx, triangle) in triangles.iter().enumerate() {
+        let normal = normal(triangle);
+        let double_area = normal.norm();
+        if double_area > 0.0 {
+            let unit_normal = normal / double_area;
+            let quadric = Quadric::from_plane(
+                unit_normal,
+                -unit_normal.dot(&position(triangle[0])),
+                double_area / 2.0,
+            );
+            for &vertex in triangle {
+                quadrics[vertex as usize] += quadric;
+            }
+        }
+        for corner in 0..3 {
+            let (a, b) = (triangle[corner], triangle[(corner + 1) % 3]);
+            vertex_triangles[a as usize].push(triangle_idx);
+            *edge_uses.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+    let mut locked = vec![false; vertex_count];
+    for ((a, b), uses) in edge_uses {
+        if uses != 2 {
+            locked[a as usize] = true;
+            locked[b as usize] = true;
+        }
+    }
+
+    let mut versions = vec![0; vertex_count];
+    let mut removed = vec![false; vertex_count];
+    let mut dead = vec![false; triangles.len()];
+    let mut heap = BinaryHeap::new();
+    let push_collapse = |heap: &mut BinaryHeap<Collapse>,
+                         quadrics: &[Quadric],
+                         versions: &[u32],
+                         from: u32,
+                         to: u32| {
+        if locked[from as usize] {
+            return;
+        }
+        heap.push(Collapse {
+            cost: (quadrics[from as usize] + quadrics[to as usize]).error(&position(to)),
+            from,
+            to,
+            from_version: versions[from as usize],
+            to_version: versions[to as usize],
+        });
+    };
+    for triangle in &triangles {
+        for corner in 0..3 {
+            let (a, b) = (triangle[corner], triangle[(corner + 1) % 3]);
+            push_collapse(&mut heap, &quadrics, &versions, a, b);
+            push_collapse(&mut heap, &quadrics, &versions, b, a);
+        }
+    }
+
+    let mut triangle_count = triangles.len();
+    let mut max_distance = 0.0f64;
+    while triangle_count > target_triangle_count
+        && let Some(collapse) = heap.pop()
+    {
+        let (from, to) = (collapse.from as usize, collapse.to as usize);
+        if removed[from]
+            || removed[to]
+            || versions[from] != collapse.from_version
+            || versions[to] != collapse.to_version
+        {
+            continue;
+        }
+        // Moving the vertex must not flip any of the triangles it keeps
+        let flips = vertex_triangles[from].iter().any(|&triangle_idx| {
+            let triangle = triangles[triangle_idx];
+            if dead[triangle_idx] || triangle.contains(&collapse.to) {
+                return false;
+            }
+            let moved = triangle.map(|vertex| {
+                if vertex as usize == from {
+                    collapse.to
+                } else {
+                    vertex
+                }
+            });
+            normal(&moved).dot(&normal(&triangle)) <= 0.0
+        });
+        if flips {
+            continue;
+        }
+
+        for triangle_idx in std::mem::take(&mut vertex_triangles[from]) {
+            if dead[triangle_idx] {
+                continue;
+            }
+            let triangle = &mut triangles[triangle_idx];
+            if triangle.contains(&collapse.to) {
+                dead[triangle_idx] = true;
+                triangle_count -= 1;
+            } else {
+                for vertex in triangle.iter_mut() {
+                    if *vertex as usize == from {
+                        *vertex = collapse.to;
+                    }
+                }
+                vertex_triangles[to].push(triangle_idx);
+            }
+        }
+        let from_quadric = quadrics[from];
+        quadrics[to] += from_quadric;
+        removed[from] = true;
+        versions[to] += 1;
+        max_distance = max_distance.max(quadrics[to].distance(&position(collapse.to)));
+
+        let mut neighbors = vertex_triangles[to]
+            .iter()
+            .filter(|&&triangle_idx| !dead[triangle_idx])
+            .flat_map(|&triangle_idx| triangles[triangle_idx])
+            .filter(|&vertex| vertex != collapse.to)
+            .collect::<Vec<_>>();
+        neighbors.sort_unstable();
+        neighbors.dedup();
+        for neighbor in neighbors {
+            push_collapse(&mut heap, &quadrics, &versions, neighbor, collapse.to);
+            push_collapse(&mut heap, &quadrics, &versions, collapse.to, neighbor);
+        }
+    }
+
+    let simplified = triangles
+        .iter()
+        .zip(dead)
+        .filter(|(_, dead)| !dead)
+        .flat_map(|(triangle, _)| *triangle)
+        .collect();
+    (simplified, max_distance as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::Primitive;
+
+    #[test]
+    fn lods_get_coarser() {
+        let mut mesh = Primitive::UvSphere {
+            segments: 64,
+            rings: 32,
+        }
+        .mesh();
+        mesh_optimizer::optimize_mesh(&mut mesh);
+        let lods = lod_chain(&mesh.positions, &mesh.indices);
+        assert!(!lods.is_empty());
+        assert!(lods.len() <= MAX_LOD_LEVELS);
+        let triangle_counts = std::iter::once(mesh.indices.len() / 3)
+            .chain(lods.iter().map(|lod| lod.indices.len() / 3))
+            .collect::<Vec<_>>();
+        assert!(
+            triangle_counts.is_sorted_by(|a, b| a >= b),
+            "{triangle_counts:?}"
+        );
+        let errors = std::iter::once(0.0)
+            .chain(lods.iter().map(|lod| lod.error))
+            .collect::<Vec<_>>();
+        assert!(errors.is_sorted_by(|a, b| a <= b), "{errors:?}");
+        // Only indices of the mesh's own vertices
+        let vertex_count = mesh.positions.len() / 3;
+        assert!(
+            lods.iter()
+                .flat_map(|lod| &lod.indices)
+                .all(|&index| (index as usize) < vertex_count)
+        );
+    }
+
+    #[test]
+    fn small_meshes_have_no_lods() {
+        let mesh = Primitive::Box { subdivisions: 1 }.mesh();
+        assert!(lod_chain(&mesh.positions, &mesh.indices).is_empty());
+    }
+}
@@ -1,12 +1,25 @@
-use std::f32::consts::PI;
+use std::{
+    cell::RefCell,
+    f32::consts::PI,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 
 use egui::ahash::HashMap;
 use image::{ImageBuffer, ImageReader, Rgba, RgbaImage};
-use itertools::izip;
+use indexmap::IndexMap;
+use itertools::{Itertools, izip};
 use nalgebra::{Matrix4, Point3, Vector3};
 use tobj::{Material, Mesh};
 
-use crate::renderer::VertexData;
+use crate::{
+    bounds::{Aabb, BoundingSphere, Bounds},
+    mesh_optimizer,
+    mesh_simplifier::{self, Lod},
+    primitives::Primitive,
+    renderer::{ObjectData, VertexData},
+};
 
 #[derive(Debug, Clone)]
 pub struct Camera {
@@ -35,12 +48,34 @@ impl Camera {
         self.rotation.x = nalgebra::clamp(new_rotation.x, -PI * 89.0 / 180.0, PI * 89.0 / 180.0);
         self.rotation.y = new_rotation.y
     }
-    pub fn tm(&self) -> Matrix4<f32> {
+    /// World position of the camera once orbited around `look_at`.
+    pub fn eye(&self) -> Point3<f32> {
         let tm_x = Matrix4::new_rotation(Vector3::new(self.rotation.x, 0.0, 0.0));
         let tm_y = Matrix4::new_rotation(Vector3::new(0.0, self.rotation.y, 0.0));
-        let position = (tm_y * tm_x).transform_point(&self.position);
-        let transform_matrix =
-            Matrix4::look_at_rh(&position, &self.look_at, &Vector3::new(0.0, 1.0, 0.0));
+        self.look_at + (tm_y * tm_x).transform_vector(&(self.position - self.look_at))
+    }
+    /// Orbits around the center of the sphere, far enough to see all of it.
+    pub fn frame(&mut self, sphere: &BoundingSphere) {
+        let half_fovy = self.fovy / 2.0;
+        let half_fovx = (half_fovy.tan() * self.aspect_ratio).atan();
+        let distance = sphere.radius / half_fovy.min(half_fovx).sin();
+        self.look_at = sphere.center;
+        self.position = sphere.center + Vector3::new(0.0, 0.0, distance.max(self.near_bound));
+    }
+    /// Height of the sphere on screen, as a fraction of the viewport's, roughly.
+    pub fn projected_size(&self, sphere: &BoundingSphere) -> f32 {
+        let distance = (sphere.center - self.eye()).norm();
+        if distance <= sphere.radius {
+            return f32::INFINITY;
+        }
+        sphere.radius / (distance * (self.fovy / 2.0).tan())
+    }
+    /// World to view space transform.
+    pub fn view(&self) -> Matrix4<f32> {
+        Matrix4::look_at_rh(&self.eye(), &self.look_at, &Vector3::new(0.0, 1.0, 0.0))
+    }
+    pub fn tm(&self) -> Matrix4<f32> {
+        let transform_matrix = self.view();
         let perspective_matrix = Matrix4::new_perspective(
             self.aspect_ratio,
             self.fovy,
@@ -53,46 +88,214 @@ impl Camera {
 
 #[derive(Debug, Clone)]
 pub struct Model {
+    pub name: String,
+    pub visible: bool,
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    /// Of all the meshes, in model space.
+    pub bounds: Bounds,
+    /// Of each mesh, in model space.
+    pub mesh_bounds: Vec<Bounds>,
+    /// Simplified versions of each mesh, coarser and coarser.
+    pub lods: Vec<Vec<Lod>>,
     pub translation: Vector3<f32>,
     pub rotation: Vector3<f32>,
     pub scaling: Vector3<f32>,
+    /// Copies of the model drawn by the same draw calls, as transforms relative to the model's
+    /// own. Without any, the model is drawn once.
+    pub instances: Vec<Matrix4<f32>>,
+    /// File the model was loaded from, none for generated ones.
+    pub obj_path: Option<PathBuf>,
+    pub mtl_paths: Vec<PathBuf>,
 }
 impl Model {
-    pub fn new(
-        obj_path: &str,
+    /// Loads an OBJ file and the MTL files it refers to. Errors name the file that failed.
+    pub fn load(
+        obj_path: impl AsRef<Path>,
+        transform: (Vector3<f32>, Vector3<f32>, Vector3<f32>),
+    ) -> Result<Self, String> {
+        let obj_path = obj_path.as_ref();
+        let obj_error = |error: &dyn std::fmt::Display| format!("{}: {error}", obj_path.display());
+        let mut reader = BufReader::new(File::open(obj_path).map_err(|error| obj_error(&error))?);
+        // Same as `tobj::load_obj`, remembering which MTL files were loaded
+        let mtl_paths = RefCell::new(vec![]);
+        let (models, materials_result) = tobj::load_obj_buf(
+            &mut reader,
+            &tobj::LoadOptions {
+                single_index: true,
+                triangulate: true,
+                ..Default::default()
+            },
+            |mtl_path| {
+                let mtl_path = obj_path.parent().unwrap_or(Path::new("")).join(mtl_path);
+                mtl_paths.borrow_mut().push(mtl_path.clone());
+                tobj::load_mtl(mtl_path)
+            },
+        )
+        .map_err(|error| obj_error(&error))?;
+        // Materials of all the MTL files are merged, along with their errors
+        let mtl_paths = mtl_paths.into_inner();
+        let materials = materials_result.map_err(|error| {
+            let mtl_paths = mtl_paths.iter().map(|path| path.display()).join(", ");
+            format!("{mtl_paths}: {error}")
+        })?;
+        let name = obj_path
+            .file_stem()
+            .map_or(obj_path.to_string_lossy(), |stem| stem.to_string_lossy())
+            .into_owned();
+        let meshes = models.into_iter().map(|model| model.mesh).collect();
+        let mut model = Self::from_meshes(name, meshes, materials, transform);
+        model.obj_path = Some(obj_path.to_path_buf());
+        model.mtl_paths = mtl_paths;
+        Ok(model)
+    }
+    /// A generated shape, of a single mesh without any material.
+    pub fn primitive(
+        primitive: Primitive,
+        transform: (Vector3<f32>, Vector3<f32>, Vector3<f32>),
+    ) -> Self {
+        Self::from_meshes(
+            primitive.name().to_string(),
+            vec![primitive.mesh()],
+            vec![],
+            transform,
+        )
+    }
+    fn from_meshes(
+        name: String,
+        mut meshes: Vec<Mesh>,
+        materials: Vec<Material>,
         (initial_position, initial_rotation, initial_scaling): (
             Vector3<f32>,
             Vector3<f32>,
             Vector3<f32>,
         ),
     ) -> Self {
-        let (models, materials_result) = tobj::load_obj(
-            obj_path,
-            &tobj::LoadOptions {
-                single_index: true,
-                triangulate: true,
-                ..Default::default()
-            },
+        for mesh in &mut meshes {
+            mesh_optimizer::optimize_mesh(mesh);
+        }
+        let lods = meshes
+            .iter()
+            .map(|mesh| mesh_simplifier::lod_chain(&mesh.positions, &mesh.indices))
+            .collect();
+        Self::with_lods(
+            name,
+            meshes,
+            lods,
+            materials,
+            (initial_position, initial_rotation, initial_scaling),
         )
-        .unwrap();
-        let materials = materials_result.unwrap();
+    }
+    /// A model of meshes that are already optimized and come with their levels of detail.
+    pub fn with_lods(
+        name: String,
+        meshes: Vec<Mesh>,
+        lods: Vec<Vec<Lod>>,
+        materials: Vec<Material>,
+        (initial_position, initial_rotation, initial_scaling): (
+            Vector3<f32>,
+            Vector3<f32>,
+            Vector3<f32>,
+        ),
+    ) -> Self {
+        let bounds = Bounds::from_positions(
+            &meshes
+                .iter()
+                .flat_map(|mesh| mesh.positions.iter().copied())
+                .collect_vec(),
+        );
+        let mesh_bounds = meshes
+            .iter()
+            .map(|mesh| Bounds::from_positions(&mesh.positions))
+            .collect();
 
         Self {
-            meshes: models.into_iter().map(|model| model.mesh).collect(),
+            name,
+            visible: true,
+            meshes,
             materials,
+            bounds,
+            mesh_bounds,
+            lods,
             translation: initial_position,
             rotation: initial_rotation,
             scaling: initial_scaling,
+            instances: vec![],
+            obj_path: None,
+            mtl_paths: vec![],
         }
     }
+    /// Scales, then rotates, then translates the model, so that `translation` is where its origin
+    /// ends up in the world.
     pub fn tm(&self) -> Matrix4<f32> {
-        Matrix4::new_rotation(self.rotation)
-            .append_nonuniform_scaling(&self.scaling)
-            .prepend_translation(&self.translation)
+        Matrix4::new_translation(&self.translation)
+            * Matrix4::new_rotation(self.rotation)
+            * Matrix4::new_nonuniform_scaling(&self.scaling)
+    }
+    pub fn instance_count(&self) -> usize {
+        self.instances.len().max(1)
     }
-    pub fn vertex_data(&self, model_idx: usize) -> Vec<Vec<VertexData>> {
+    /// World transform of every instance.
+    pub fn instance_tms(&self) -> Vec<Matrix4<f32>> {
+        let tm = self.tm();
+        if self.instances.is_empty() {
+            vec![tm]
+        } else {
+            self.instances
+                .iter()
+                .map(|instance| tm * instance)
+                .collect()
+        }
+    }
+    /// Places `columns` by `rows` instances side by side on the XZ plane of the model, or goes
+    /// back to a single one.
+    pub fn instance_grid(&mut self, columns: usize, rows: usize) {
+        if columns * rows <= 1 {
+            self.instances.clear();
+            return;
+        }
+        let spacing = self.bounds.aabb.size() * 1.5;
+        self.instances = (0..rows)
+            .cartesian_product(0..columns)
+            .map(|(row, column)| {
+                Matrix4::new_translation(&Vector3::new(
+                    column as f32 * spacing.x,
+                    0.0,
+                    row as f32 * spacing.z,
+                ))
+            })
+            .collect();
+    }
+    /// Bounds of all the instances, in world space.
+    pub fn world_bounds(&self) -> Bounds {
+        self.transform_bounds(&self.bounds)
+    }
+    fn transform_bounds(&self, bounds: &Bounds) -> Bounds {
+        let instance_bounds = self
+            .instance_tms()
+            .iter()
+            .map(|tm| bounds.transform(tm))
+            .collect_vec();
+        if let [bounds] = instance_bounds[..] {
+            return bounds;
+        }
+        let aabb = instance_bounds
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, bounds| aabb.merge(&bounds.aabb));
+        Bounds {
+            aabb,
+            sphere: BoundingSphere::from_aabb(&aabb),
+        }
+    }
+    /// Scales the model uniformly so that its largest dimension is `size`.
+    pub fn fit_to_size(&mut self, size: f32) {
+        let largest_dimension = self.bounds.aabb.size().max();
+        if largest_dimension > 0.0 {
+            self.scaling = Vector3::repeat(size / largest_dimension);
+        }
+    }
+    /// Vertex data of every mesh, independent of the objects drawing it.
+    pub fn vertex_data(&self) -> Vec<Vec<VertexData>> {
         let mut vertex_data = vec![];
         for mesh in &self.meshes {
             let raw_positions = &mesh.positions;
@@ -104,40 +307,114 @@ impl Model {
             } else {
                 raw_normals.chunks_exact(3).clone()
             };
-            let uvs = mesh.texcoords.chunks_exact(2).clone();
+            // Files without texture coordinates get them all at 0, so that no vertex is left out
+            let zero_uvs: Vec<f32> = vec![0.0; raw_positions.len() / 3 * 2];
+            let uvs = if mesh.texcoords.is_empty() {
+                zero_uvs.chunks_exact(2).clone()
+            } else {
+                mesh.texcoords.chunks_exact(2).clone()
+            };
             vertex_data.push(
                 izip!(positions, normals, uvs)
                     .map(|(position, normal, uv)| VertexData {
                         position: position.try_into().unwrap(),
                         normal: normal.try_into().unwrap(),
                         uv: [uv[0], 1.0 - uv[1]],
-                        model_idx: model_idx as u32,
                     })
                     .collect::<Vec<_>>(),
             )
         }
         vertex_data
     }
+    /// Per-object data of every instance of every mesh, the instances of a mesh being contiguous.
+    pub fn object_data(&self, textures_map: &IndexMap<String, RgbaImage>) -> Vec<ObjectData> {
+        let instance_tms = self.instance_tms();
+        self.meshes
+            .iter()
+            .flat_map(|mesh| {
+                let material = mesh
+                    .material_id
+                    .map(|material_id| &self.materials[material_id]);
+                let [r, g, b] = material.and_then(|m| m.diffuse).unwrap_or([1.0; 3]);
+                let opacity = material.map_or(1.0, |m| m.opacity());
+                let texture_idx = self.texture_idx(mesh, textures_map);
+                instance_tms.iter().map(move |tm| ObjectData {
+                    model_matrix: (*tm).into(),
+                    normal_matrix: normal_matrix(tm),
+                    diffuse: [r, g, b, opacity],
+                    texture_idx,
+                    _padding: [0; 3],
+                })
+            })
+            .collect()
+    }
+    /// Texture of the mesh's material in the renderer's texture array, 0 being the default white
+    /// texture of materials without one.
+    pub fn texture_idx(&self, mesh: &Mesh, textures_map: &IndexMap<String, RgbaImage>) -> u32 {
+        mesh.material_id
+            .map(|material_id| &self.materials[material_id])
+            .filter(|m| m.has_texture())
+            .and_then(|m| textures_map.get_index_of(&m.name))
+            .map_or(0, |idx| idx as u32 + 1)
+    }
+    pub fn alpha_mode(&self, mesh: &Mesh) -> AlphaMode {
+        mesh.material_id.map_or(AlphaMode::Opaque, |material_id| {
+            self.materials[material_id].alpha_mode()
+        })
+    }
     pub fn debugg(&self) {
-        let vertex_data = &self.vertex_data(0)[0];
+        let vertex_data = &self.vertex_data()[0];
         for (i, vertex) in vertex_data.iter().enumerate() {
             println!(
                 "{:?}: {:?}\t\t{:?}\t\t{:?}",
                 i, &vertex.position, vertex.normal, vertex.uv
             );
         }
-        println!("");
+        println!();
         for (i, chunk) in self.meshes[0].indices.chunks_exact(3).enumerate() {
             println!("{:?}: {:?}", i, chunk);
         }
     }
 }
 
-trait MaterialExt {
+/// Inverse transpose of the rotation and scaling of a transform, which keeps normals perpendicular
+/// to the surface under non-uniform scaling. Columns are padded to 4 floats like WGSL's `mat3x3`.
+fn normal_matrix(tm: &Matrix4<f32>) -> [[f32; 4]; 3] {
+    let normal_matrix = tm
+        .fixed_view::<3, 3>(0, 0)
+        .try_inverse()
+        .unwrap_or_default()
+        .transpose();
+    [0, 1, 2].map(|column| {
+        let [x, y, z] = normal_matrix.column(column).into();
+        [x, y, z, 0.0]
+    })
+}
+
+/// How the material of a mesh covers what is behind it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlphaMode {
+    #[default]
+    Opaque,
+    /// Either opaque or fully transparent, where the alpha of its `map_d` texture is under half.
+    Cutout,
+    /// Partly transparent by its dissolve, blended over what is behind it.
+    Blend,
+}
+
+pub trait MaterialExt {
     fn texture_data<'a>(
         &self,
         textures_map: &'a HashMap<String, RgbaImage>,
     ) -> Option<&'a RgbaImage>;
+    /// Opacity from `d`, or from `Tr` which some exporters write instead, as `1 - Tr`.
+    fn opacity(&self) -> f32;
+    fn alpha_mode(&self) -> AlphaMode;
+    /// Files of the diffuse and dissolve textures, combined into a single texture.
+    fn texture_names(&self) -> impl Iterator<Item = &str>;
+    fn has_texture(&self) -> bool {
+        self.texture_names().next().is_some()
+    }
 }
 impl MaterialExt for Material {
     fn texture_data<'a>(
@@ -148,4 +425,60 @@ impl MaterialExt for Material {
             .as_ref()
             .map(|dt_name| &textures_map[dt_name])
     }
+    fn opacity(&self) -> f32 {
+        self.dissolve
+            .or_else(|| {
+                let transparency: f32 = self.unknown_param.get("Tr")?.trim().parse().ok()?;
+                Some(1.0 - transparency)
+            })
+            .unwrap_or(1.0)
+    }
+    fn alpha_mode(&self) -> AlphaMode {
+        if self.opacity() < 1.0 {
+            AlphaMode::Blend
+        } else if self.dissolve_texture.is_some() {
+            AlphaMode::Cutout
+        } else {
+            AlphaMode::Opaque
+        }
+    }
+    fn texture_names(&self) -> impl Iterator<Item = &str> {
+        self.diffuse_texture
+            .as_deref()
+            .into_iter()
+            .chain(self.dissolve_texture.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn vertices_without_texture_coordinates_are_kept() {
+        let obj_path = std::env::temp_dir().join("webgpu-tinkering-no-uvs.obj");
+        fs::write(
+            &obj_path,
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\nf 1 2 3\nf 1 3 4\n",
+        )
+        .unwrap();
+        let model = Model::load(
+            &obj_path,
+            (Vector3::zeros(), Vector3::zeros(), Vector3::repeat(1.0)),
+        )
+        .unwrap();
+        fs::remove_file(&obj_path).unwrap();
+        let mesh = &model.meshes[0];
+        assert!(mesh.texcoords.is_empty());
+        let vertex_data = model.vertex_data();
+        assert_eq!(vertex_data[0].len(), 4);
+        assert!(
+            mesh.indices
+                .iter()
+                .all(|&idx| (idx as usize) < vertex_data[0].len())
+        );
+        assert!(vertex_data[0].iter().all(|vertex| vertex.uv == [0.0, 1.0]));
+    }
 }
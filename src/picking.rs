@@ -0,0 +1,128 @@
+use nalgebra::{Point3, Vector3};
+
+use crate::{models::Camera, scene::Scene};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+impl Ray {
+    /// Ray going from the camera through a point of the viewport, `position` being in pixels
+    /// from the top left corner.
+    pub fn from_viewport(camera: &Camera, position: [f32; 2], viewport_size: [f32; 2]) -> Self {
+        let ndc_x = 2.0 * position[0] / viewport_size[0] - 1.0;
+        let ndc_y = 1.0 - 2.0 * position[1] / viewport_size[1];
+        let inverse_tm = camera.tm().try_inverse().unwrap();
+        // nalgebra's perspective maps the near plane to a depth of -1
+        let near = inverse_tm.transform_point(&Point3::new(ndc_x, ndc_y, -1.0));
+        let far = inverse_tm.transform_point(&Point3::new(ndc_x, ndc_y, 1.0));
+        Self {
+            origin: near,
+            direction: (far - near).normalize(),
+        }
+    }
+    pub fn at(&self, distance: f32) -> Point3<f32> {
+        self.origin + self.direction * distance
+    }
+}
+
+/// Closest triangle hit by a ray.
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub model_idx: usize,
+    pub instance_idx: usize,
+    pub mesh_idx: usize,
+    pub triangle_idx: usize,
+    pub position: Point3<f32>,
+    pub distance: f32,
+}
+
+/// Casts a ray against the triangles of every visible model.
+pub fn pick(scene: &Scene, ray: &Ray) -> Option<Hit> {
+    let mut closest: Option<Hit> = None;
+    for (model_idx, model) in scene.models.iter().enumerate() {
+        if !model.visible {
+            continue;
+        }
+        for (instance_idx, tm) in model.instance_tms().into_iter().enumerate() {
+            let Some(inverse_tm) = tm.try_inverse() else {
+                continue;
+            };
+            // Intersect in model space, distances are brought back to world space afterwards
+            let local_ray = Ray {
+                origin: inverse_tm.transform_point(&ray.origin),
+                direction: inverse_tm.transform_vector(&ray.direction),
+            };
+            if model.bounds.aabb.intersect_ray(&local_ray).is_none() {
+                continue;
+            }
+            for (mesh_idx, mesh) in model.meshes.iter().enumerate() {
+                if model.mesh_bounds[mesh_idx]
+                    .aabb
+                    .intersect_ray(&local_ray)
+                    .is_none()
+                {
+                    continue;
+                }
+                let vertex = |idx: u32| {
+                    let i = idx as usize * 3;
+                    Point3::new(
+                        mesh.positions[i],
+                        mesh.positions[i + 1],
+                        mesh.positions[i + 2],
+                    )
+                };
+                for (triangle_idx, triangle) in mesh.indices.chunks_exact(3).enumerate() {
+                    let Some(t) = intersect_triangle(
+                        &local_ray,
+                        [
+                            vertex(triangle[0]),
+                            vertex(triangle[1]),
+                            vertex(triangle[2]),
+                        ],
+                    ) else {
+                        continue;
+                    };
+                    let position = tm.transform_point(&local_ray.at(t));
+                    let distance = (position - ray.origin).norm();
+                    if closest.is_none_or(|hit| distance < hit.distance) {
+                        closest = Some(Hit {
+                            model_idx,
+                            instance_idx,
+                            mesh_idx,
+                            triangle_idx,
+                            position,
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    closest
+}
+
+/// Möller–Trumbore intersection, returns the ray parameter of the hit.
+fn intersect_triangle(ray: &Ray, [a, b, c]: [Point3<f32>; 3]) -> Option<f32> {
+    let edge_1 = b - a;
+    let edge_2 = c - a;
+    let p = ray.direction.cross(&edge_2);
+    let determinant = edge_1.dot(&p);
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+    let inverse_determinant = 1.0 / determinant;
+    let s = ray.origin - a;
+    let u = s.dot(&p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(&edge_1);
+    let v = ray.direction.dot(&q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge_2.dot(&q) * inverse_determinant;
+    (t > 0.0).then_some(t)
+}
@@ -0,0 +1,485 @@
+use std::{collections::HashMap, f32::consts::PI};
+
+use nalgebra::Vector3;
+use tobj::Mesh;
+
+/// Shapes generated instead of loaded from a file, centered on the origin and fitting in a unit
+/// cube. Texture coordinates follow the OBJ convention, with V pointing up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Primitive {
+    /// On the XZ plane, facing up.
+    Plane {
+        subdivisions: u32,
+    },
+    Box {
+        subdivisions: u32,
+    },
+    /// With `segments` around the Y axis and `rings` from pole to pole.
+    UvSphere {
+        segments: u32,
+        rings: u32,
+    },
+    /// Each subdivision splits every triangle of an icosahedron in 4.
+    IcoSphere {
+        subdivisions: u32,
+    },
+    Cylinder {
+        segments: u32,
+    },
+    Cone {
+        segments: u32,
+    },
+    /// Around the Y axis, `sides` being the segments around the tube and `minor_radius` its
+    /// radius.
+    Torus {
+        segments: u32,
+        sides: u32,
+        minor_radius: f32,
+    },
+    /// A cylinder ended by half spheres of `rings` each, twice as tall as it is wide.
+    Capsule {
+        segments: u32,
+        rings: u32,
+    },
+}
+impl Primitive {
+    /// Every primitive, with default subdivisions.
+    pub const ALL: [Primitive; 8] = [
+        Primitive::Plane { subdivisions: 1 },
+        Primitive::Box { subdivisions: 1 },
+        Primitive::UvSphere {
+            segments: 32,
+            rings: 16,
+        },
+        Primitive::IcoSphere { subdivisions: 3 },
+        Primitive::Cylinder { segments: 32 },
+        Primitive::Cone { segments: 32 },
+        Primitive::Torus {
+            segments: 48,
+            sides: 24,
+            minor_radius: 0.15,
+        },
+        Primitive::Capsule {
+            segments: 32,
+            rings: 8,
+        },
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Primitive::Plane { .. } => "Plane",
+            Primitive::Box { .. } => "Box",
+            Primitive::UvSphere { .. } => "UV sphere",
+            Primitive::IcoSphere { .. } => "Ico sphere",
+            Primitive::Cylinder { .. } => "Cylinder",
+            Primitive::Cone { .. } => "Cone",
+            Primitive::Torus { .. } => "Torus",
+            Primitive::Capsule { .. } => "Capsule",
+        }
+    }
+    /// Name in scene description files.
+    pub fn identifier(&self) -> &'static str {
+        match self {
+            Primitive::Plane { .. } => "plane",
+            Primitive::Box { .. } => "box",
+            Primitive::UvSphere { .. } => "uv_sphere",
+            Primitive::IcoSphere { .. } => "ico_sphere",
+            Primitive::Cylinder { .. } => "cylinder",
+            Primitive::Cone { .. } => "cone",
+            Primitive::Torus { .. } => "torus",
+            Primitive::Capsule { .. } => "capsule",
+        }
+    }
+    /// Sets a parameter from its field name and textual value, as written in scene description
+    /// files.
+    pub fn set_parameter(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let primitive_name = self.name();
+        let parse_error = |error: &dyn std::fmt::Display| format!("{name}={value}: {error}");
+        match (self, name) {
+            (
+                Primitive::Plane {
+                    subdivisions: count,
+                }
+                | Primitive::Box {
+                    subdivisions: count,
+                }
+                | Primitive::IcoSphere {
+                    subdivisions: count,
+                },
+                "subdivisions",
+            )
+            | (
+                Primitive::UvSphere {
+                    segments: count, ..
+                }
+                | Primitive::Cylinder { segments: count }
+                | Primitive::Cone { segments: count }
+                | Primitive::Torus {
+                    segments: count, ..
+                }
+                | Primitive::Capsule {
+                    segments: count, ..
+                },
+                "segments",
+            )
+            | (
+                Primitive::UvSphere { rings: count, .. } | Primitive::Capsule { rings: count, .. },
+                "rings",
+            )
+            | (Primitive::Torus { sides: count, .. }, "sides") => {
+                *count = value.parse().map_err(|error| parse_error(&error))?
+            }
+            (Primitive::Torus { minor_radius, .. }, "minor_radius") => {
+                *minor_radius = value.parse().map_err(|error| parse_error(&error))?;
+            }
+            _ => return Err(format!("{primitive_name} has no parameter {name}")),
+        }
+        Ok(())
+    }
+    /// Generates the triangulated, single-indexed mesh, like the OBJ loader would load it.
+    pub fn mesh(&self) -> Mesh {
+        let mut builder = MeshBuilder::default();
+        match *self {
+            Primitive::Plane { subdivisions } => {
+                builder.grid(
+                    Vector3::new(-0.5, 0.0, 0.5),
+                    Vector3::x(),
+                    -Vector3::z(),
+                    subdivisions.max(1),
+                );
+            }
+            Primitive::Box { subdivisions } => {
+                // Each face as U and V axes, their cross product pointing out of the box
+                let faces = [
+                    (-Vector3::z(), Vector3::y()),
+                    (Vector3::z(), Vector3::y()),
+                    (Vector3::x(), -Vector3::z()),
+                    (Vector3::x(), Vector3::z()),
+                    (Vector3::x(), Vector3::y()),
+                    (-Vector3::x(), Vector3::y()),
+                ];
+                for (u_axis, v_axis) in faces {
+                    let normal = u_axis.cross(&v_axis);
+                    let origin = (normal - u_axis - v_axis) / 2.0;
+                    builder.grid(origin, u_axis, v_axis, subdivisions.max(1));
+                }
+            }
+            Primitive::UvSphere { segments, rings } => {
+                let rings = rings.max(2);
+                let profile = (0..=rings)
+                    .map(|ring| {
+                        let latitude = PI * (ring as f32 / rings as f32 - 0.5);
+                        ProfilePoint::on_circle(0.5, 0.0, latitude, ring as f32 / rings as f32)
+                    })
+                    .collect::<Vec<_>>();
+                builder.lathe(&profile, segments.max(3));
+            }
+            Primitive::IcoSphere { subdivisions } => builder.ico_sphere(subdivisions),
+            Primitive::Cylinder { segments } => {
+                let segments = segments.max(3);
+                builder.lathe(
+                    &[
+                        ProfilePoint::new(0.5, -0.5, 1.0, 0.0, 0.0),
+                        ProfilePoint::new(0.5, 0.5, 1.0, 0.0, 1.0),
+                    ],
+                    segments,
+                );
+                builder.disk(0.5, segments, true);
+                builder.disk(-0.5, segments, false);
+            }
+            Primitive::Cone { segments } => {
+                let segments = segments.max(3);
+                // The slope of the side is the same all the way up, and so are its normals
+                let normal = Vector3::new(1.0, 0.5, 0.0).normalize();
+                builder.lathe(
+                    &[
+                        ProfilePoint::new(0.5, -0.5, normal.x, normal.y, 0.0),
+                        ProfilePoint::new(0.0, 0.5, normal.x, normal.y, 1.0),
+                    ],
+                    segments,
+                );
+                builder.disk(-0.5, segments, false);
+            }
+            Primitive::Torus {
+                segments,
+                sides,
+                minor_radius,
+            } => {
+                let sides = sides.max(3);
+                let minor_radius = minor_radius.clamp(0.01, 0.25);
+                let major_radius = 0.5 - minor_radius;
+                // From the inside of the tube, below, around to the outside and back
+                let profile = (0..=sides)
+                    .map(|side| {
+                        let v = side as f32 / sides as f32;
+                        ProfilePoint::on_circle(minor_radius, major_radius, 2.0 * PI * v - PI, v)
+                    })
+                    .collect::<Vec<_>>();
+                builder.lathe(&profile, segments.max(3));
+            }
+            Primitive::Capsule { segments, rings } => {
+                let rings = rings.max(1);
+                let radius = 0.25;
+                let half_height = 0.5 - radius;
+                // The equators of both half spheres are joined by the cylinder
+                let profile = (0..=rings)
+                    .map(|ring| (ring as f32 / rings as f32 - 1.0, -half_height))
+                    .chain((0..=rings).map(|ring| (ring as f32 / rings as f32, half_height)))
+                    .map(|(t, y)| {
+                        let point = ProfilePoint::on_circle(radius, 0.0, t * PI / 2.0, 0.0);
+                        // Textured by height, for the cylinder not to be squeezed in between
+                        let y = point.y + y;
+                        ProfilePoint {
+                            y,
+                            v: y + 0.5,
+                            ..point
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                builder.lathe(&profile, segments.max(3));
+            }
+        }
+        builder.mesh
+    }
+}
+
+/// Point of the profile swept around the Y axis by `MeshBuilder::lathe`, in the XY plane.
+#[derive(Debug, Clone, Copy)]
+struct ProfilePoint {
+    radius: f32,
+    y: f32,
+    normal_radius: f32,
+    normal_y: f32,
+    v: f32,
+}
+impl ProfilePoint {
+    fn new(radius: f32, y: f32, normal_radius: f32, normal_y: f32, v: f32) -> Self {
+        Self {
+            radius,
+            y,
+            normal_radius,
+            normal_y,
+            v,
+        }
+    }
+    /// On a circle of the given radius centered at `center_radius` from the axis, `angle` being
+    /// the latitude.
+    fn on_circle(radius: f32, center_radius: f32, angle: f32, v: f32) -> Self {
+        // Exactly on the axis at the poles, for the triangles there to be dropped
+        let (sin, cos) = if (angle.abs() - PI / 2.0).abs() < 1e-6 {
+            (angle.signum(), 0.0)
+        } else {
+            angle.sin_cos()
+        };
+        Self::new(center_radius + radius * cos, radius * sin, cos, sin, v)
+    }
+}
+
+#[derive(Default)]
+struct MeshBuilder {
+    mesh: Mesh,
+}
+impl MeshBuilder {
+    fn vertex(&mut self, position: Vector3<f32>, normal: Vector3<f32>, uv: [f32; 2]) -> u32 {
+        let vertex = self.mesh.positions.len() as u32 / 3;
+        self.mesh.positions.extend(position.iter());
+        self.mesh.normals.extend(normal.iter());
+        self.mesh.texcoords.extend(uv);
+        vertex
+    }
+    fn position(&self, vertex: u32) -> &[f32] {
+        let start = vertex as usize * 3;
+        &self.mesh.positions[start..start + 3]
+    }
+    /// Adds a counterclockwise triangle, unless two of its corners are at the same place.
+    fn triangle(&mut self, a: u32, b: u32, c: u32) {
+        let [pa, pb, pc] = [a, b, c].map(|vertex| self.position(vertex));
+        if pa == pb || pb == pc || pc == pa {
+            return;
+        }
+        self.mesh.indices.extend([a, b, c]);
+    }
+    /// Two triangles between rows of `columns + 1` vertices, the second row facing the first
+    /// one counterclockwise.
+    fn strip(&mut self, first_row: u32, second_row: u32, columns: u32) {
+        for column in 0..columns {
+            let [a, b] = [first_row + column, first_row + column + 1];
+            let [c, d] = [second_row + column + 1, second_row + column];
+            self.triangle(a, b, c);
+            self.triangle(a, c, d);
+        }
+    }
+    /// Unit square from `origin` along the axes, facing along their cross product.
+    fn grid(
+        &mut self,
+        origin: Vector3<f32>,
+        u_axis: Vector3<f32>,
+        v_axis: Vector3<f32>,
+        subdivisions: u32,
+    ) {
+        let normal = u_axis.cross(&v_axis);
+        let mut rows = vec![];
+        for row in 0..=subdivisions {
+            let v = row as f32 / subdivisions as f32;
+            rows.push(self.mesh.positions.len() as u32 / 3);
+            for column in 0..=subdivisions {
+                let u = column as f32 / subdivisions as f32;
+                self.vertex(origin + u_axis * u + v_axis * v, normal, [u, v]);
+            }
+        }
+        for (first_row, second_row) in rows.iter().zip(&rows[1..]) {
+            self.strip(*first_row, *second_row, subdivisions);
+        }
+    }
+    /// Sweeps the profile around the Y axis, starting towards +Z and turning towards +X. The
+    /// profile goes up for the surface to face out.
+    fn lathe(&mut self, profile: &[ProfilePoint], segments: u32) {
+        let mut rows = vec![];
+        for point in profile {
+            rows.push(self.mesh.positions.len() as u32 / 3);
+            for segment in 0..=segments {
+                let u = segment as f32 / segments as f32;
+                // The last column closes the seam, at exactly the same place as the first one
+                let (sin, cos) = if segment == segments {
+                    (0.0, 1.0)
+                } else {
+                    (2.0 * PI * u).sin_cos()
+                };
+                self.vertex(
+                    Vector3::new(point.radius * sin, point.y, point.radius * cos),
+                    Vector3::new(
+                        point.normal_radius * sin,
+                        point.normal_y,
+                        point.normal_radius * cos,
+                    ),
+                    [u, point.v],
+                );
+            }
+        }
+        for (first_row, second_row) in rows.iter().zip(&rows[1..]) {
+            self.strip(*first_row, *second_row, segments);
+        }
+    }
+    /// Cap of radius 0.5 at the given height, facing up or down.
+    fn disk(&mut self, y: f32, segments: u32, up: bool) {
+        let normal = if up { Vector3::y() } else { -Vector3::y() };
+        let center = self.vertex(Vector3::new(0.0, y, 0.0), normal, [0.5, 0.5]);
+        let first = self.mesh.positions.len() as u32 / 3;
+        for segment in 0..segments {
+            let (sin, cos) = (2.0 * PI * segment as f32 / segments as f32).sin_cos();
+            let position = Vector3::new(0.5 * sin, y, 0.5 * cos);
+            // Seen from outside, as if the cap was unfolded from the side
+            let uv = [
+                0.5 + position.x,
+                0.5 + if up { -position.z } else { position.z },
+            ];
+            self.vertex(position, normal, uv);
+        }
+        for segment in 0..segments {
+            let a = first + segment;
+            let b = first + (segment + 1) % segments;
+            if up {
+                self.triangle(center, a, b);
+            } else {
+                self.triangle(center, b, a);
+            }
+        }
+    }
+    /// Sphere of radius 0.5 made of triangles of about the same size, by splitting the faces of
+    /// an icosahedron and pushing the new vertices out to the sphere.
+    fn ico_sphere(&mut self, subdivisions: u32) {
+        let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+        let mut positions = [
+            [-1.0, t, 0.0],
+            [1.0, t, 0.0],
+            [-1.0, -t, 0.0],
+            [1.0, -t, 0.0],
+            [0.0, -1.0, t],
+            [0.0, 1.0, t],
+            [0.0, -1.0, -t],
+            [0.0, 1.0, -t],
+            [t, 0.0, -1.0],
+            [t, 0.0, 1.0],
+            [-t, 0.0, -1.0],
+            [-t, 0.0, 1.0],
+        ]
+        .map(|position| Vector3::from(position).normalize())
+        .to_vec();
+        let mut triangles = vec![
+            [0, 11, 5],
+            [0, 5, 1],
+            [0, 1, 7],
+            [0, 7, 10],
+            [0, 10, 11],
+            [1, 5, 9],
+            [5, 11, 4],
+            [11, 10, 2],
+            [10, 7, 6],
+            [7, 1, 8],
+            [3, 9, 4],
+            [3, 4, 2],
+            [3, 2, 6],
+            [3, 6, 8],
+            [3, 8, 9],
+            [4, 9, 5],
+            [2, 4, 11],
+            [6, 2, 10],
+            [8, 6, 7],
+            [9, 8, 1],
+        ];
+        for _ in 0..subdivisions.min(6) {
+            let mut midpoints = HashMap::new();
+            let mut midpoint = |a: usize, b: usize| {
+                *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    positions.push(((positions[a] + positions[b]) / 2.0).normalize());
+                    positions.len() - 1
+                })
+            };
+            triangles = triangles
+                .into_iter()
+                .flat_map(|[a, b, c]| {
+                    let [ab, bc, ca] = [midpoint(a, b), midpoint(b, c), midpoint(c, a)];
+                    [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+                })
+                .collect();
+        }
+
+        // Vertices are added for every corner, the ones the triangles share are merged when
+        // the mesh is optimized
+        // Same longitudes as the lathe, starting towards +Z
+        let longitude =
+            |position: &Vector3<f32>| (position.x.atan2(position.z) / (2.0 * PI)).rem_euclid(1.0);
+        let latitude = |position: &Vector3<f32>| position.y.asin() / PI + 0.5;
+        for triangle in triangles {
+            let corners = triangle.map(|vertex| positions[vertex]);
+            let on_pole = corners.map(|position| position.x == 0.0 && position.z == 0.0);
+            let mut us = corners.map(|position| longitude(&position));
+            let off_pole_us = (0..3)
+                .filter(|&corner| !on_pole[corner])
+                .map(|corner| us[corner])
+                .collect::<Vec<_>>();
+            // Triangles across the seam wrap around the texture instead of running back across it
+            let (min_u, max_u) = off_pole_us
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(min, max), &u| {
+                    (min.min(u), max.max(u))
+                });
+            let wraps = max_u - min_u > 0.5;
+            let wrap = |u: f32| if wraps && u < 0.5 { u + 1.0 } else { u };
+            // The longitude at the poles is the one of the rest of the triangle
+            let pole_u =
+                off_pole_us.iter().map(|&u| wrap(u)).sum::<f32>() / off_pole_us.len() as f32;
+            for corner in 0..3 {
+                us[corner] = if on_pole[corner] {
+                    pole_u
+                } else {
+                    wrap(us[corner])
+                };
+            }
+            let vertices = [0, 1, 2].map(|corner| {
+                let position = corners[corner];
+                self.vertex(position / 2.0, position, [us[corner], latitude(&position)])
+            });
+            self.triangle(vertices[0], vertices[1], vertices[2]);
+        }
+    }
+}
@@ -2,15 +2,50 @@ use std::{borrow::Cow, collections::HashMap, num::NonZero};
 
 use bytemuck::{Pod, Zeroable};
 use eframe::wgpu::{self, util::DeviceExt};
-use image::{EncodableLayout, RgbaImage};
-use indexmap::IndexMap;
+use image::{EncodableLayout, Rgba, RgbaImage};
 use itertools::Itertools;
+use log::{error, info};
 use wgpu::TextureUsages;
 
-use crate::{
-    models::{Camera, Model},
-    winit_app::Scene,
-};
+use crate::bounds::Frustum;
+use crate::models::{AlphaMode, Model};
+use crate::scene::Scene;
+
+mod debug_draw;
+mod geometry_pool;
+mod gpu_culling;
+mod grid;
+mod lines;
+mod outline;
+mod pipelines;
+mod preprocessor;
+mod shaders;
+
+pub use debug_draw::{AXIS_COLORS, DebugDraw};
+pub use geometry_pool::{GeometryHandle, GeometryPool, GeometryRange};
+pub use grid::GridPass;
+pub use lines::LineVertex;
+pub use outline::OutlinePass;
+pub use pipelines::{PipelineKey, ScenePass};
+pub use shaders::{ShaderFile, ShaderWatcher};
+
+use gpu_culling::{CullInput, CullingResources, GpuCulling};
+use lines::LinePass;
+use outline::OUTLINE_DIRECTIONS;
+use pipelines::{PipelineRegistry, create_scene_pipeline};
+use preprocessor::Defines;
+use shaders::ShaderSources;
+
+pub const REQUIRED_FEATURES: wgpu::Features = wgpu::Features::TEXTURE_BINDING_ARRAY
+    .union(wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING);
+/// Features used when the adapter supports them, `POLYGON_MODE_LINE` for the wireframe view,
+/// `INDIRECT_FIRST_INSTANCE` for GPU culling, `MULTI_DRAW_INDIRECT` to submit its draws at once and
+/// `PIPELINE_CACHE` to keep the compiled pipelines between runs.
+pub const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::POLYGON_MODE_LINE
+    .union(wgpu::Features::INDIRECT_FIRST_INSTANCE)
+    .union(wgpu::Features::MULTI_DRAW_INDIRECT)
+    .union(wgpu::Features::PIPELINE_CACHE);
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24PlusStencil8;
 
 pub async fn init() -> (wgpu::Instance, wgpu::Adapter, wgpu::Device, wgpu::Queue) {
     let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
@@ -26,8 +61,7 @@ pub async fn init() -> (wgpu::Instance, wgpu::Adapter, wgpu::Device, wgpu::Queue
     let (device, queue) = adapter
         .request_device(
             &wgpu::DeviceDescriptor {
-                required_features: wgpu::Features::TEXTURE_BINDING_ARRAY
-                    | wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING,
+                required_features: REQUIRED_FEATURES | (adapter.features() & OPTIONAL_FEATURES),
                 ..Default::default()
             },
             None,
@@ -37,218 +71,739 @@ pub async fn init() -> (wgpu::Instance, wgpu::Adapter, wgpu::Device, wgpu::Queue
     dbg!(&device.features());
     (instance, adapter, device, queue)
 }
+
+fn create_bind_group_layout(device: &wgpu::Device, textures_count: usize) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("bind group layout"),
+        entries: &[
+            // Storage Buffer
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // Texture Array
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: Some(NonZero::new(textures_count as u32).unwrap()),
+            },
+            // Sampler
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            // Uniform Buffer
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+/// Variant of the scene shader, specialized when it is compiled through the defines of the
+/// preprocessor.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShaderPermutation {
+    pub debug_view: DebugView,
+    /// Whether materials sample their diffuse texture, untextured ones having only a color.
+    pub textured: bool,
+    /// Whether fragments under half alpha are discarded, for `AlphaMode::Cutout` materials.
+    pub alpha_cutout: bool,
+}
+impl ShaderPermutation {
+    fn defines(&self) -> Defines {
+        let mut defines = Defines::from([
+            (
+                "DEBUG_VIEW".to_string(),
+                format!("{}u", self.debug_view as u32),
+            ),
+            // The same for all permutations, but must match the outline pass's draws
+            (
+                "OUTLINE_DIRECTIONS".to_string(),
+                format!("{OUTLINE_DIRECTIONS}u"),
+            ),
+        ]);
+        if self.textured {
+            defines.insert("TEXTURED".to_string(), String::new());
+        }
+        if self.alpha_cutout {
+            defines.insert("ALPHA_CUTOUT".to_string(), String::new());
+        }
+        defines
+    }
+}
+
+/// Alternative ways of shading the scene, to inspect its geometry and materials.
+#[repr(u32)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DebugView {
+    #[default]
+    Shaded = 0,
+    Wireframe = 1,
+    WireframeOnShaded = 2,
+    Normals = 3,
+    UvChecker = 4,
+    Depth = 5,
+    MeshColors = 6,
+    Backfaces = 7,
+}
+impl DebugView {
+    pub const ALL: [DebugView; 8] = [
+        DebugView::Shaded,
+        DebugView::Wireframe,
+        DebugView::WireframeOnShaded,
+        DebugView::Normals,
+        DebugView::UvChecker,
+        DebugView::Depth,
+        DebugView::MeshColors,
+        DebugView::Backfaces,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            DebugView::Shaded => "Shaded",
+            DebugView::Wireframe => "Wireframe",
+            DebugView::WireframeOnShaded => "Wireframe on shaded",
+            DebugView::Normals => "Normals",
+            DebugView::UvChecker => "UV checker",
+            DebugView::Depth => "Depth",
+            DebugView::MeshColors => "Mesh colors",
+            DebugView::Backfaces => "Backfaces",
+        }
+    }
+}
+
+/// Lines showing the vertex normals of the visible models, to debug imported assets. Vertices
+/// without a normal get a red tick instead. Meshes have no tangents, so only normals are drawn,
+/// and only on the first instance of instanced models.
+#[derive(Debug, Clone, Copy)]
+pub struct NormalsOverlay {
+    pub enabled: bool,
+    /// In world units.
+    pub length: f32,
+}
+impl Default for NormalsOverlay {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            length: 0.05,
+        }
+    }
+}
+
+/// Choice of the simplified versions of the meshes by how large they are on screen. Each level
+/// has about half the triangles of the previous one, so it is drawn from half the size.
+#[derive(Debug, Clone, Copy)]
+pub struct LodSettings {
+    pub enabled: bool,
+    /// Height of a mesh's bounding sphere on screen, as a fraction of the viewport's, from which
+    /// it is drawn at full detail.
+    pub full_detail_size: f32,
+}
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            full_detail_size: 0.5,
+        }
+    }
+}
+
+/// How far past its range of sizes, in levels, a mesh must go to switch to another level of
+/// detail, so that it doesn't flicker between two levels at their boundary.
+const LOD_HYSTERESIS: f32 = 0.25;
+
+/// Where meshes outside of the camera frustum are skipped, if at all.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CullingMode {
+    Off,
+    #[default]
+    Cpu,
+    /// By a compute shader writing indirect draws, the CPU never knows which meshes are drawn.
+    Gpu,
+}
+impl CullingMode {
+    pub const ALL: [CullingMode; 3] = [CullingMode::Off, CullingMode::Cpu, CullingMode::Gpu];
+    pub fn name(&self) -> &'static str {
+        match self {
+            CullingMode::Off => "Off",
+            CullingMode::Cpu => "CPU",
+            CullingMode::Gpu => "GPU",
+        }
+    }
+}
+
+/// How many meshes of the visible models the last frame drew and skipped for being outside of
+/// the view, and how many triangles the drawn ones had at their level of detail.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CullingStats {
+    pub drawn: usize,
+    pub culled: usize,
+    /// Of the drawn meshes, at their level of detail.
+    pub triangles: usize,
+}
+
+/// Per-frame uniforms, shared by every pipeline.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+struct FrameData {
+    projection: [[f32; 4]; 4],
+    inverse_projection: [[f32; 4]; 4],
+    view: [[f32; 4]; 4],
+    normal_length: f32,
+    aspect_ratio: f32,
+    _padding: [u32; 2],
+}
+impl FrameData {
+    fn new(scene: &Scene, normals: &NormalsOverlay) -> Self {
+        let projection = scene.camera.tm();
+        Self {
+            projection: projection.into(),
+            inverse_projection: projection.try_inverse().unwrap_or_default().into(),
+            view: scene.camera.view().into(),
+            normal_length: normals.length,
+            aspect_ratio: scene.camera.aspect_ratio,
+            _padding: [0; 2],
+        }
+    }
+}
+
+const INDIRECT_ARGS_SIZE: u64 = std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>() as u64;
+
+const SILHOUETTE_STENCIL_FACE: wgpu::StencilFaceState = wgpu::StencilFaceState {
+    compare: wgpu::CompareFunction::Always,
+    fail_op: wgpu::StencilOperation::Keep,
+    depth_fail_op: wgpu::StencilOperation::Replace,
+    pass_op: wgpu::StencilOperation::Replace,
+};
+
+/// Blends transparent meshes over what is behind them but leaves the alpha channel alone, as
+/// egui blends the viewport texture over the panels with it.
+const TRANSPARENT_BLEND: wgpu::BlendState = wgpu::BlendState {
+    color: wgpu::BlendState::ALPHA_BLENDING.color,
+    alpha: wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::Zero,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    },
+};
+
+/// How to draw a single mesh from the geometry pool.
+pub struct MeshDraw {
+    /// Edge indices, each edge of the triangles once, are only allocated when lines can't be
+    /// rasterized from the triangles themselves.
+    pub geometry: GeometryRange,
+    /// Instances of the model, each one reading the object following the previous one's.
+    pub instance_count: u32,
+    /// Object of the first instance, which the draws pass as their first instance.
+    pub first_object_idx: u32,
+    /// Index of the mesh's arguments in the indirect draw buffer.
+    pub draw_idx: u32,
+    /// Whether its material has a diffuse texture, drawn with the textured shader permutation.
+    pub textured: bool,
+}
+
+/// GPU resources of a loaded scene, created once and updated every frame.
+pub struct SceneResources {
+    pub meshes: Vec<Vec<MeshDraw>>,
+    storage_buffer: wgpu::Buffer,
+    /// First object of every draw, for the pipelines needing the instance index for something
+    /// else.
+    first_object_buffer: wgpu::Buffer,
+    uniform_buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+    culling: CullingResources,
+}
+
+/// Offscreen color and depth textures the scene can be rendered into, with their own size and
+/// sample count. The resolved color texture can be sampled afterwards.
+pub struct RenderTarget {
+    pub size: [u32; 2],
+    pub color_texture_view: wgpu::TextureView,
+    msaa_texture_view: Option<wgpu::TextureView>,
+    depth_texture_view: wgpu::TextureView,
+}
+
 pub struct Renderer<'a> {
     device: Cow<'a, wgpu::Device>,
     queue: Cow<'a, wgpu::Queue>,
     surface: Option<wgpu::Surface<'a>>,
+    depth_texture_view: Option<wgpu::TextureView>,
+    target_format: wgpu::TextureFormat,
+    sample_count: u32,
+    textures_count: usize,
     bind_group_layout: wgpu::BindGroupLayout,
-    pub render_pipeline: wgpu::RenderPipeline,
+    pub shaders: ShaderWatcher,
+    /// The pipelines are created from, kept to recreate them when the bind group layout changes.
+    shader_sources: ShaderSources,
+    pipelines: PipelineRegistry,
+    geometry: GeometryPool,
+    /// Allocations of every mesh of every model of the scene.
+    model_geometry: Vec<Vec<GeometryHandle>>,
+    pub debug_view: DebugView,
+    pub normals: NormalsOverlay,
+    pub outline: OutlinePass,
+    pub grid: GridPass,
+    depth_tested_lines: LinePass,
+    on_top_lines: LinePass,
+    /// Shapes to draw in the next frame only.
+    pub debug: DebugDraw,
+    /// Skip meshes outside of the camera frustum.
+    pub culling: CullingMode,
+    gpu_culling: GpuCulling,
+    culling_stats: CullingStats,
+    /// Whether each mesh of each model is drawn in the current frame.
+    mesh_visibility: Vec<Vec<bool>>,
+    pub lod: LodSettings,
+    /// Level of detail each mesh of each model is drawn at, kept between frames for hysteresis.
+    mesh_lods: Vec<Vec<usize>>,
+    /// Alpha mode of the material of each mesh of each model, updated every frame as materials
+    /// can be edited.
+    mesh_alpha_modes: Vec<Vec<AlphaMode>>,
+    surface_size: [u32; 2],
+    resources: Option<SceneResources>,
 }
 impl<'a> Renderer<'a> {
     pub fn new(
         device: Cow<'a, wgpu::Device>,
         queue: Cow<'a, wgpu::Queue>,
-        textures_count: usize,
+        adapter_info: &wgpu::AdapterInfo,
+        target_format: wgpu::TextureFormat,
+        sample_count: u32,
     ) -> Self {
-        let shader_module = device.create_shader_module(wgpu::include_wgsl!("shader.wgsl"));
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("bind group layout"),
-            entries: &[
-                // Storage Buffer
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                // Texture Array
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false,
-                    },
-                    count: Some(NonZero::new(textures_count as u32).unwrap()),
-                },
-                // Sampler
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                // Uniform Buffer
-                wgpu::BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
-        });
-        let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("pipeline layout descriptor"),
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
-
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("render pipeline descriptor"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader_module,
-                entry_point: Some("vs_main"),
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<VertexData>() as u64,
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &[
-                        // Position
-                        wgpu::VertexAttribute {
-                            format: wgpu::VertexFormat::Float32x3,
-                            offset: 0,
-                            shader_location: 0,
-                        },
-                        // Normal
-                        wgpu::VertexAttribute {
-                            format: wgpu::VertexFormat::Float32x3,
-                            offset: std::mem::size_of::<[f32; 3]>() as u64,
-                            shader_location: 1,
-                        },
-                        // UV
-                        wgpu::VertexAttribute {
-                            format: wgpu::VertexFormat::Float32x2,
-                            offset: std::mem::size_of::<[f32; 3 + 3]>() as u64,
-                            shader_location: 2,
-                        },
-                        // Model Index
-                        wgpu::VertexAttribute {
-                            format: wgpu::VertexFormat::Uint32,
-                            offset: std::mem::size_of::<[f32; 3 + 3 + 2]>() as u64,
-                            shader_location: 3,
-                        },
-                    ],
-                }],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader_module,
-                entry_point: Some("fs_main"),
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                //cull_mode: None,
-                unclipped_depth: false,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                conservative: false,
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: wgpu::TextureFormat::Depth24PlusStencil8,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-            cache: None,
-        });
+        // Only the default white texture until a scene is loaded
+        let textures_count = 1;
+        let bind_group_layout = create_bind_group_layout(&device, textures_count);
+        let pipelines = PipelineRegistry::new(&device, adapter_info);
+        let shader_sources = ShaderSources::embedded();
+        let scene_shader = shader_sources
+            .create_module(
+                &device,
+                ShaderFile::Scene,
+                &ShaderPermutation::default().defines(),
+            )
+            .unwrap();
+        let culling_shader = shader_sources
+            .create_module(&device, ShaderFile::Culling, &Defines::new())
+            .unwrap();
+        let outline = OutlinePass::new(
+            &device,
+            &scene_shader,
+            pipelines.cache(),
+            &bind_group_layout,
+            target_format,
+            sample_count,
+        );
+        let gpu_culling = GpuCulling::new(&device, &culling_shader, pipelines.cache());
+        let geometry = GeometryPool::new(&device);
+        let grid = GridPass::new(
+            &device,
+            &scene_shader,
+            pipelines.cache(),
+            &bind_group_layout,
+            target_format,
+            sample_count,
+        );
+        let depth_tested_lines = LinePass::new(
+            &device,
+            &scene_shader,
+            pipelines.cache(),
+            &bind_group_layout,
+            target_format,
+            sample_count,
+            true,
+        );
+        let on_top_lines = LinePass::new(
+            &device,
+            &scene_shader,
+            pipelines.cache(),
+            &bind_group_layout,
+            target_format,
+            sample_count,
+            false,
+        );
 
         Self {
             device,
             queue,
             surface: None,
+            depth_texture_view: None,
+            target_format,
+            sample_count,
+            textures_count,
             bind_group_layout,
-            render_pipeline,
+            shaders: ShaderWatcher::default(),
+            shader_sources,
+            pipelines,
+            geometry,
+            model_geometry: vec![],
+            debug_view: DebugView::default(),
+            normals: NormalsOverlay::default(),
+            outline,
+            grid,
+            depth_tested_lines,
+            on_top_lines,
+            debug: DebugDraw::default(),
+            culling: CullingMode::default(),
+            gpu_culling,
+            culling_stats: CullingStats::default(),
+            mesh_visibility: vec![],
+            lod: LodSettings::default(),
+            mesh_lods: vec![],
+            mesh_alpha_modes: vec![],
+            surface_size: [1, 1],
+            resources: None,
         }
     }
     pub fn add_surface(&mut self, size: [u32; 2], surface: wgpu::Surface<'a>) {
-        surface.configure(
+        self.surface = Some(surface);
+        self.resize(size);
+    }
+    pub fn resize(&mut self, size: [u32; 2]) {
+        if let Some(surface) = &self.surface {
+            surface.configure(
+                &self.device,
+                &wgpu::SurfaceConfiguration {
+                    usage: TextureUsages::RENDER_ATTACHMENT,
+                    format: self.target_format,
+                    width: size[0],
+                    height: size[1],
+                    present_mode: wgpu::PresentMode::Fifo,
+                    desired_maximum_frame_latency: 2,
+                    alpha_mode: wgpu::CompositeAlphaMode::Auto,
+                    view_formats: vec![],
+                },
+            );
+            self.depth_texture_view = Some(self.create_depth_texture_view(size));
+            self.surface_size = size;
+        }
+    }
+    /// Uploads the scene to the GPU, replacing any previously loaded one. Models added or removed
+    /// afterwards go through `add_models` and `remove_model`.
+    pub fn load_scene(&mut self, scene: &Scene) {
+        self.geometry.clear();
+        self.model_geometry.clear();
+        self.add_models(scene);
+    }
+    /// Uploads the geometry of the models added at the end of the scene since it was loaded.
+    pub fn add_models(&mut self, scene: &Scene) {
+        for model in &scene.models[self.model_geometry.len()..] {
+            let handles = self.allocate_model(model);
+            self.model_geometry.push(handles);
+        }
+        info!(
+            "Geometry pool: {} KiB",
+            self.geometry.used_bytes().div_ceil(1024)
+        );
+        self.refresh_scene(scene);
+    }
+    /// Replaces the geometry of a model that was loaded again.
+    pub fn reload_model(&mut self, scene: &Scene, model_idx: usize) {
+        for handle in std::mem::take(&mut self.model_geometry[model_idx]) {
+            self.geometry.free(handle);
+        }
+        self.geometry.compact(&self.device, &self.queue);
+        self.model_geometry[model_idx] = self.allocate_model(&scene.models[model_idx]);
+        self.refresh_scene(scene);
+    }
+    fn allocate_model(&mut self, model: &Model) -> Vec<GeometryHandle> {
+        let polygon_mode_line = self
+            .device
+            .features()
+            .contains(wgpu::Features::POLYGON_MODE_LINE);
+        model
+            .vertex_data()
+            .iter()
+            .zip(&model.meshes)
+            .zip(&model.lods)
+            .map(|((mesh_vertex_data, mesh), lods)| {
+                let edge_indices = if polygon_mode_line {
+                    vec![]
+                } else {
+                    edge_indices(&mesh.indices)
+                };
+                let lod_indices = std::iter::once(&mesh.indices[..])
+                    .chain(lods.iter().map(|lod| &lod.indices[..]))
+                    .collect_vec();
+                self.geometry.allocate(
+                    &self.device,
+                    &self.queue,
+                    mesh_vertex_data,
+                    &lod_indices,
+                    &edge_indices,
+                )
+            })
+            .collect()
+    }
+    /// Frees the geometry of a model already removed from the scene, compacting what remains.
+    pub fn remove_model(&mut self, scene: &Scene, model_idx: usize) {
+        for handle in self.model_geometry.remove(model_idx) {
+            self.geometry.free(handle);
+        }
+        self.geometry.compact(&self.device, &self.queue);
+        self.refresh_scene(scene);
+    }
+    /// Recreates the resources of the scene other than its geometry, such as after the instances
+    /// of a model changed.
+    pub fn refresh_scene(&mut self, scene: &Scene) {
+        // The texture array size is part of the bind group layout
+        let textures_count = scene.textures_map.len() + 1;
+        if textures_count != self.textures_count {
+            self.textures_count = textures_count;
+            self.bind_group_layout = create_bind_group_layout(&self.device, textures_count);
+            if let Err(message) = self.catch_validation_errors(Self::rebuild_pipelines) {
+                error!("Recreating the pipelines failed: {message}");
+            }
+        }
+        self.resources = Some(self.create_resources(scene));
+    }
+    /// Recreates the pipelines from the shader sources, only for the scene pipelines created so
+    /// far.
+    fn rebuild_pipelines(&mut self) -> Result<(), String> {
+        let device = &self.device;
+        let scene_shader = self.shader_sources.create_module(
+            device,
+            ShaderFile::Scene,
+            &ShaderPermutation::default().defines(),
+        )?;
+        let culling_shader =
+            self.shader_sources
+                .create_module(device, ShaderFile::Culling, &Defines::new())?;
+        self.outline.rebuild_pipeline(
+            device,
+            &scene_shader,
+            self.pipelines.cache(),
+            &self.bind_group_layout,
+            self.target_format,
+            self.sample_count,
+        );
+        self.grid.rebuild_pipeline(
+            device,
+            &scene_shader,
+            self.pipelines.cache(),
+            &self.bind_group_layout,
+            self.target_format,
+            self.sample_count,
+        );
+        self.depth_tested_lines.rebuild_pipeline(
+            device,
+            &scene_shader,
+            self.pipelines.cache(),
+            &self.bind_group_layout,
+            self.target_format,
+            self.sample_count,
+        );
+        self.on_top_lines.rebuild_pipeline(
+            device,
+            &scene_shader,
+            self.pipelines.cache(),
+            &self.bind_group_layout,
+            self.target_format,
+            self.sample_count,
+        );
+        self.gpu_culling
+            .rebuild_pipeline(device, &culling_shader, self.pipelines.cache());
+        for key in self.pipelines.clear() {
+            let pipeline = self.create_pipeline(&key)?;
+            self.pipelines.insert(key, Some(pipeline));
+        }
+        Ok(())
+    }
+    fn create_pipeline(&self, key: &PipelineKey) -> Result<wgpu::RenderPipeline, String> {
+        let shader_module = self.shader_sources.create_module(
             &self.device,
-            &wgpu::SurfaceConfiguration {
-                usage: TextureUsages::RENDER_ATTACHMENT,
-                format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                width: size[0],
-                height: size[1],
-                present_mode: wgpu::PresentMode::Fifo,
-                desired_maximum_frame_latency: 2,
-                alpha_mode: wgpu::CompositeAlphaMode::Auto,
-                view_formats: vec![],
+            ShaderFile::Scene,
+            &key.permutation.defines(),
+        )?;
+        Ok(create_scene_pipeline(
+            &self.device,
+            &shader_module,
+            &self.bind_group_layout,
+            key,
+            self.pipelines.cache(),
+        ))
+    }
+    /// Key of the scene pipeline drawing a pass with the current debug view. Only the shaded pass
+    /// depends on the materials, blended ones being drawn without writing the depth.
+    fn pipeline_key(&self, pass: ScenePass, textured: bool, alpha_mode: AlphaMode) -> PipelineKey {
+        let shaded = pass == ScenePass::Shaded;
+        let blended = shaded && alpha_mode == AlphaMode::Blend;
+        PipelineKey {
+            permutation: ShaderPermutation {
+                debug_view: self.debug_view,
+                textured: shaded && textured,
+                alpha_cutout: shaded && alpha_mode == AlphaMode::Cutout,
             },
-        );
-        self.surface = Some(surface);
+            pass,
+            target_format: self.target_format,
+            sample_count: self.sample_count,
+            cull_mode: (shaded && self.debug_view != DebugView::Backfaces)
+                .then_some(wgpu::Face::Back),
+            blend: Some(if blended {
+                TRANSPARENT_BLEND
+            } else {
+                wgpu::BlendState::REPLACE
+            }),
+            depth_write: shaded && !blended,
+        }
     }
-
-    pub fn create_resources(
-        &self,
-        surface_size: [u32; 2],
-        camera: &Camera,
-        textures_map: &IndexMap<String, RgbaImage>,
-        models: &[Model],
-    ) -> (
-        Vec<Vec<wgpu::Buffer>>,
-        Vec<Vec<wgpu::Buffer>>,
-        wgpu::BindGroup,
-        wgpu::TextureView,
-    ) {
+    /// Creates the scene pipelines the next frame draws with, when they are needed for the first
+    /// time. Those failing to compile are skipped when drawing.
+    fn prepare_pipelines(&mut self, scene: &Scene) {
+        self.mesh_alpha_modes = scene
+            .models
+            .iter()
+            .map(|model| {
+                model
+                    .meshes
+                    .iter()
+                    .map(|mesh| model.alpha_mode(mesh))
+                    .collect()
+            })
+            .collect();
+        let Some(resources) = &self.resources else {
+            return;
+        };
+        let this = &*self;
+        let materials = resources
+            .meshes
+            .iter()
+            .enumerate()
+            .flat_map(|(model_idx, meshes)| {
+                meshes.iter().enumerate().map(move |(mesh_idx, mesh)| {
+                    (mesh.textured, this.mesh_alpha_mode(model_idx, mesh_idx))
+                })
+            })
+            .unique()
+            .collect_vec();
+        let mut passes = vec![];
+        if self.debug_view != DebugView::Wireframe {
+            passes.push(ScenePass::Shaded);
+        }
+        if matches!(
+            self.debug_view,
+            DebugView::Wireframe | DebugView::WireframeOnShaded
+        ) {
+            passes.push(ScenePass::Wireframe);
+        }
+        if self.normals.enabled {
+            passes.push(ScenePass::Normals);
+        }
+        for pass in passes {
+            for &(textured, alpha_mode) in &materials {
+                let key = self.pipeline_key(pass, textured, alpha_mode);
+                if self.pipelines.contains(&key) {
+                    continue;
+                }
+                let pipeline =
+                    match self.catch_validation_errors(|renderer| renderer.create_pipeline(&key)) {
+                        Ok(pipeline) => Some(pipeline),
+                        Err(message) => {
+                            error!("Compiling the pipeline {key:?} failed: {message}");
+                            self.shaders.last_error = Some(message);
+                            None
+                        }
+                    };
+                self.pipelines.insert(key, pipeline);
+            }
+        }
+    }
+    /// Writes the pipeline cache to disk, when the backend has one, so that the next runs
+    /// compile their pipelines faster.
+    pub fn save_pipeline_cache(&self) {
+        self.pipelines.save_cache();
+    }
+    /// Runs `f`, returning the first validation error it causes on the device, if any, instead of
+    /// leaving it to the uncaptured error handler, which panics by default.
+    fn catch_validation_errors<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let result = f(self);
+        match pollster::block_on(self.device.pop_error_scope()) {
+            Some(validation_error) => Err(validation_error.to_string()),
+            None => result,
+        }
+    }
+    /// Recompiles the shaders and recreates the pipelines when the shader files changed, while
+    /// hot reloading them. Shaders that fail to compile leave the previous pipelines in place,
+    /// with the error in `shaders.last_error`. Returns whether the pipelines were recreated.
+    pub fn reload_changed_shaders(&mut self) -> bool {
+        if !self.shaders.poll() {
+            return false;
+        }
+        match self.reload_shaders() {
+            Ok(()) => {
+                info!("Reloaded shaders");
+                self.shaders.last_error = None;
+                true
+            }
+            Err(message) => {
+                error!("Reloading shaders failed: {message}");
+                self.shaders.last_error = Some(message);
+                false
+            }
+        }
+    }
+    fn reload_shaders(&mut self) -> Result<(), String> {
+        let previous_sources = std::mem::replace(&mut self.shader_sources, ShaderSources::read()?);
+        let result = self.catch_validation_errors(Self::rebuild_pipelines);
+        if result.is_err() {
+            self.shader_sources = previous_sources;
+            if let Err(message) = self.catch_validation_errors(Self::rebuild_pipelines) {
+                error!("Restoring the previous shaders failed: {message}");
+            }
+        }
+        result
+    }
+    pub fn create_resources(&self, scene: &Scene) -> SceneResources {
         let device = &self.device;
-        let mut vertex_buffers = vec![];
-        let mut index_buffers = vec![];
-        let mut tms_flat = vec![];
+        let mut meshes = vec![];
+        let mut draw_count = 0;
+        let mut objects = vec![];
         let mut texture_views = vec![];
 
-        for (i, model) in models.iter().enumerate() {
-            let model_vertex_buffers = model
-                .vertex_data(i)
+        for (model, handles) in scene.models.iter().zip(&self.model_geometry) {
+            let model_meshes = handles
                 .iter()
-                .map(|mesh_vertex_data| {
-                    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                        label: Some("buffer init descriptor (vertex)"),
-                        contents: bytemuck::cast_slice(mesh_vertex_data.as_slice()),
-                        usage: wgpu::BufferUsages::VERTEX,
-                    })
-                })
-                .collect_vec();
-            let model_index_buffers = model
-                .meshes
-                .iter()
-                .map(|mesh| {
-                    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                        label: Some("buffer init descriptor (indices)"),
-                        contents: bytemuck::cast_slice(mesh.indices.as_slice()),
-                        usage: wgpu::BufferUsages::INDEX,
-                    })
+                .enumerate()
+                .zip(&model.meshes)
+                .map(|((mesh_idx, &handle), mesh)| MeshDraw {
+                    geometry: self.geometry.range(handle),
+                    instance_count: model.instance_count() as u32,
+                    first_object_idx: (objects.len() + mesh_idx * model.instance_count()) as u32,
+                    draw_idx: (draw_count + mesh_idx) as u32,
+                    textured: model.texture_idx(mesh, &scene.textures_map) != 0,
                 })
                 .collect_vec();
 
-            vertex_buffers.push(model_vertex_buffers);
-            index_buffers.push(model_index_buffers);
-            tms_flat.extend_from_slice(model.tm().as_slice())
+            draw_count += model_meshes.len();
+            meshes.push(model_meshes);
+            objects.extend(model.object_data(&scene.textures_map));
         }
-        for image in textures_map.values() {
+        // Texture 0 is plain white, used by materials without a diffuse texture
+        let default_texture = RgbaImage::from_pixel(1, 1, Rgba([255, 255, 255, 255]));
+        for image in std::iter::once(&default_texture).chain(scene.textures_map.values()) {
             let texture_size = wgpu::Extent3d {
                 width: image.width(),
                 height: image.height(),
@@ -281,41 +836,42 @@ impl<'a> Renderer<'a> {
             );
             texture_views.push(texture.create_view(&wgpu::TextureViewDescriptor::default()));
         }
+        // Texture coordinates past the edges wrap around, as in OBJ files and across the seams of
+        // generated primitives
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
             mag_filter: wgpu::FilterMode::Nearest,
             min_filter: wgpu::FilterMode::Nearest,
             mipmap_filter: wgpu::FilterMode::Nearest,
             ..Default::default()
         });
-        //dbg!(&tms_flat);
         let storage_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("buffer init descriptor (storage)"),
-            contents: bytemuck::cast_slice(&tms_flat),
+            contents: bytemuck::cast_slice(&objects),
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
+        let first_objects = meshes
+            .iter()
+            .flatten()
+            .map(|mesh: &MeshDraw| mesh.first_object_idx)
+            .collect_vec();
+        // Vertex buffers can't be empty
+        let first_object_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("buffer init descriptor (first objects)"),
+            contents: bytemuck::cast_slice(if first_objects.is_empty() {
+                &[0]
+            } else {
+                first_objects.as_slice()
+            }),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("buffer init descriptor (uniform)"),
-            contents: bytemuck::cast_slice(camera.tm().as_slice()),
-            usage: wgpu::BufferUsages::UNIFORM,
-        });
-        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("texture descriptor (depth)"),
-            size: wgpu::Extent3d {
-                width: surface_size[0],
-                height: surface_size[1],
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Depth24PlusStencil8,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            view_formats: &[],
+            contents: bytemuck::bytes_of(&FrameData::new(scene, &self.normals)),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
-        let depth_texture_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
 
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("bind group descriptor"),
@@ -354,22 +910,502 @@ impl<'a> Renderer<'a> {
             ],
         });
 
-        (
-            vertex_buffers,
-            index_buffers,
+        let culling = self.gpu_culling.create_resources(
+            device,
+            &storage_buffer,
+            &cull_inputs(scene, &meshes, &self.mesh_lods),
+        );
+
+        SceneResources {
+            meshes,
+            culling,
+            storage_buffer,
+            first_object_buffer,
+            uniform_buffer,
             bind_group,
-            depth_texture_view,
-        )
+        }
     }
-    pub fn render(&self, surface_size: [u32; 2], scene: &Scene) -> Result<(), wgpu::SurfaceError> {
-        if let Some(surface) = &self.surface {
-            let (vertex_buffers, index_buffers, bind_group, depth_texture_view) = self
-                .create_resources(
-                    surface_size,
-                    &scene.camera,
-                    &scene.textures_map,
-                    &scene.models,
+    pub fn create_depth_texture_view(&self, size: [u32; 2]) -> wgpu::TextureView {
+        let depth_texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("texture descriptor (depth)"),
+            size: wgpu::Extent3d {
+                width: size[0],
+                height: size[1],
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: self.sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        depth_texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+    /// Uploads the camera, the per-object data and the culling inputs of the current frame.
+    pub fn update(&self, scene: &Scene) {
+        if let Some(resources) = &self.resources {
+            let objects = scene
+                .models
+                .iter()
+                .flat_map(|model| model.object_data(&scene.textures_map))
+                .collect_vec();
+            self.queue
+                .write_buffer(&resources.storage_buffer, 0, bytemuck::cast_slice(&objects));
+            self.queue.write_buffer(
+                &resources.uniform_buffer,
+                0,
+                bytemuck::bytes_of(&FrameData::new(scene, &self.normals)),
+            );
+            if self.effective_culling() == CullingMode::Gpu {
+                self.gpu_culling.update(
+                    &self.queue,
+                    &resources.culling,
+                    &Frustum::from_matrix(&scene.camera.tm()),
+                    &cull_inputs(scene, &resources.meshes, &self.mesh_lods),
+                );
+            }
+        }
+    }
+    /// Records the draw calls of the visible models into an already configured render pass,
+    /// followed by the grid, the outline of the selected one, the debug lines and the axes
+    /// indicator.
+    pub fn draw(&self, render_pass: &mut wgpu::RenderPass<'_>, scene: &Scene, size: [u32; 2]) {
+        if let Some(resources) = &self.resources {
+            let selected_model_idx = scene.selection.map(|selection| selection.model_idx());
+            render_pass.set_bind_group(0, Some(&resources.bind_group), &[]);
+            let shaded = self.debug_view != DebugView::Wireframe;
+            if shaded {
+                self.draw_models(render_pass, resources, scene, ScenePass::Shaded);
+                self.draw_blended_models(render_pass, resources, scene);
+            }
+            if matches!(
+                self.debug_view,
+                DebugView::Wireframe | DebugView::WireframeOnShaded
+            ) {
+                self.draw_models(render_pass, resources, scene, ScenePass::Wireframe);
+            }
+            if self.normals.enabled
+                && let Some(pipeline) = self.pipelines.get(&self.pipeline_key(
+                    ScenePass::Normals,
+                    false,
+                    AlphaMode::Opaque,
+                ))
+            {
+                render_pass.set_pipeline(pipeline);
+                self.geometry.bind_vertices(render_pass);
+                for (model_idx, meshes) in resources.meshes.iter().enumerate() {
+                    for (mesh_idx, mesh) in meshes.iter().enumerate() {
+                        if !self.is_mesh_visible(model_idx, mesh_idx) {
+                            continue;
+                        }
+                        render_pass.set_vertex_buffer(
+                            1,
+                            resources
+                                .first_object_buffer
+                                .slice(mesh.draw_idx as u64 * FIRST_OBJECT_SIZE..),
+                        );
+                        // The vertices of the mesh are its instances
+                        let base_vertex = mesh.geometry.base_vertex;
+                        render_pass
+                            .draw(0..2, base_vertex..base_vertex + mesh.geometry.vertex_count);
+                    }
+                }
+            }
+            self.grid.draw_grid(render_pass, &resources.bind_group);
+            self.depth_tested_lines
+                .draw(render_pass, &resources.bind_group);
+            // The outline needs the stencil written by the shaded pass
+            if let Some(model_idx) = selected_model_idx
+                && shaded
+                && scene.models[model_idx].visible
+            {
+                self.outline.draw(
+                    &self.queue,
+                    render_pass,
+                    &resources.bind_group,
+                    &self.geometry,
+                    resources.meshes[model_idx]
+                        .iter()
+                        .enumerate()
+                        .map(|(mesh_idx, mesh)| {
+                            (mesh, mesh_lod(&self.mesh_lods, model_idx, mesh_idx))
+                        }),
+                    size,
+                );
+            }
+            self.on_top_lines.draw(render_pass, &resources.bind_group);
+            self.grid.draw_axes(render_pass, &resources.bind_group);
+        }
+    }
+    /// Draws the visible models with the pipelines of a pass, as edges for the wireframe one. The
+    /// shaded pass leaves out the meshes with blended materials.
+    fn draw_models(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        resources: &SceneResources,
+        scene: &Scene,
+        pass: ScenePass,
+    ) {
+        let selected_model_idx = scene.selection.map(|selection| selection.model_idx());
+        self.geometry.bind_vertices(render_pass);
+        let edges = pass == ScenePass::Wireframe
+            && !self
+                .device
+                .features()
+                .contains(wgpu::Features::POLYGON_MODE_LINE);
+        if !edges
+            && self.effective_culling() == CullingMode::Gpu
+            && self
+                .device
+                .features()
+                .contains(wgpu::Features::MULTI_DRAW_INDIRECT)
+        {
+            self.multi_draw_models(render_pass, resources, selected_model_idx, pass);
+            return;
+        }
+        // The selected model goes last so that no other model overwrites its stencil
+        let draw_order = (0..scene.models.len())
+            .filter(|&model_idx| Some(model_idx) != selected_model_idx)
+            .chain(selected_model_idx);
+        let mut index_format = None;
+        let mut pipeline_key = None;
+        for model_idx in draw_order {
+            if !scene.models[model_idx].visible {
+                continue;
+            }
+            let stencil_reference = (Some(model_idx) == selected_model_idx) as u32;
+            render_pass.set_stencil_reference(stencil_reference);
+            for (mesh_idx, mesh) in resources.meshes[model_idx].iter().enumerate() {
+                let alpha_mode = self.mesh_alpha_mode(model_idx, mesh_idx);
+                if !self.is_mesh_visible(model_idx, mesh_idx)
+                    || (pass == ScenePass::Shaded && alpha_mode == AlphaMode::Blend)
+                {
+                    continue;
+                }
+                let key = self.pipeline_key(pass, mesh.textured, alpha_mode);
+                let Some(pipeline) = self.pipelines.get(&key) else {
+                    continue;
+                };
+                if pipeline_key != Some(key) {
+                    pipeline_key = Some(key);
+                    render_pass.set_pipeline(pipeline);
+                }
+                if index_format != Some(mesh.geometry.index_format) {
+                    index_format = Some(mesh.geometry.index_format);
+                    self.geometry
+                        .bind_indices(render_pass, mesh.geometry.index_format);
+                }
+                self.draw_mesh(render_pass, resources, model_idx, mesh_idx, edges);
+            }
+        }
+    }
+    /// Draws the visible meshes with blended materials after the rest of the shaded pass, from
+    /// the farthest to the closest so that each one blends over those behind it. The instances of
+    /// a mesh are drawn together, at the average depth of their bounds.
+    fn draw_blended_models(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        resources: &SceneResources,
+        scene: &Scene,
+    ) {
+        let selected_model_idx = scene.selection.map(|selection| selection.model_idx());
+        let view = scene.camera.view();
+        let mut blended_meshes = vec![];
+        for (model_idx, (model, meshes)) in scene.models.iter().zip(&resources.meshes).enumerate() {
+            let instance_tms = model.instance_tms();
+            for (mesh_idx, bounds) in model.mesh_bounds.iter().enumerate().take(meshes.len()) {
+                if !self.is_mesh_visible(model_idx, mesh_idx)
+                    || self.mesh_alpha_mode(model_idx, mesh_idx) != AlphaMode::Blend
+                {
+                    continue;
+                }
+                // The camera looks down -Z in view space
+                let depth = instance_tms
+                    .iter()
+                    .map(|tm| -(view * tm).transform_point(&bounds.sphere.center).z)
+                    .sum::<f32>()
+                    / instance_tms.len() as f32;
+                blended_meshes.push((depth, model_idx, mesh_idx));
+            }
+        }
+        blended_meshes.sort_by(|(depth_a, ..), (depth_b, ..)| depth_b.total_cmp(depth_a));
+        self.geometry.bind_vertices(render_pass);
+        for (_, model_idx, mesh_idx) in blended_meshes {
+            let mesh = &resources.meshes[model_idx][mesh_idx];
+            let key = self.pipeline_key(ScenePass::Shaded, mesh.textured, AlphaMode::Blend);
+            let Some(pipeline) = self.pipelines.get(&key) else {
+                continue;
+            };
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_stencil_reference((Some(model_idx) == selected_model_idx) as u32);
+            self.geometry
+                .bind_indices(render_pass, mesh.geometry.index_format);
+            self.draw_mesh(render_pass, resources, model_idx, mesh_idx, false);
+        }
+    }
+    /// Records the draw of a mesh, or of its edges, with its pipeline, vertices and indices
+    /// already bound.
+    fn draw_mesh(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        resources: &SceneResources,
+        model_idx: usize,
+        mesh_idx: usize,
+        edges: bool,
+    ) {
+        let mesh = &resources.meshes[model_idx][mesh_idx];
+        let instances = mesh.first_object_idx..mesh.first_object_idx + mesh.instance_count;
+        let base_vertex = mesh.geometry.base_vertex as i32;
+        if edges {
+            render_pass.draw_indexed(mesh.geometry.edge_indices(), base_vertex, instances);
+        } else if self.effective_culling() == CullingMode::Gpu {
+            render_pass.draw_indexed_indirect(
+                &resources.culling.indirect_buffer,
+                mesh.draw_idx as u64 * INDIRECT_ARGS_SIZE,
+            );
+        } else {
+            let lod = mesh_lod(&self.mesh_lods, model_idx, mesh_idx);
+            render_pass.draw_indexed(mesh.geometry.indices(lod), base_vertex, instances);
+        }
+    }
+    /// Submits the draws written by the culling shader with as few calls as the stencil, the
+    /// index formats and the pipelines allow: the draws of the models before and after the
+    /// selected one, then the selected one's, each split where the index format or the pipeline
+    /// changes. Hidden models have been given no instance by the shader. The shaded pass leaves
+    /// out the meshes with blended materials.
+    fn multi_draw_models(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        resources: &SceneResources,
+        selected_model_idx: Option<usize>,
+        pass: ScenePass,
+    ) {
+        let draws = resources
+            .meshes
+            .iter()
+            .enumerate()
+            .flat_map(|(model_idx, meshes)| {
+                meshes
+                    .iter()
+                    .enumerate()
+                    .map(move |(mesh_idx, mesh)| (mesh, self.mesh_alpha_mode(model_idx, mesh_idx)))
+            })
+            .collect_vec();
+        let selected_draws = selected_model_idx.map_or(draws.len()..draws.len(), |model_idx| {
+            let meshes = &resources.meshes[model_idx];
+            let first_draw_idx = meshes.first().map_or(0, |mesh| mesh.draw_idx as usize);
+            first_draw_idx..first_draw_idx + meshes.len()
+        });
+        let multi_draw = |render_pass: &mut wgpu::RenderPass<'_>, range: std::ops::Range<usize>| {
+            let runs = draws[range].iter().chunk_by(|(mesh, alpha_mode)| {
+                (
+                    mesh.geometry.index_format,
+                    self.pipeline_key(pass, mesh.textured, *alpha_mode),
+                )
+            });
+            for ((index_format, key), run) in &runs {
+                if pass == ScenePass::Shaded && key.blend == Some(TRANSPARENT_BLEND) {
+                    continue;
+                }
+                let Some(pipeline) = self.pipelines.get(&key) else {
+                    continue;
+                };
+                let run = run.collect_vec();
+                render_pass.set_pipeline(pipeline);
+                self.geometry.bind_indices(render_pass, index_format);
+                render_pass.multi_draw_indexed_indirect(
+                    &resources.culling.indirect_buffer,
+                    run[0].0.draw_idx as u64 * INDIRECT_ARGS_SIZE,
+                    run.len() as u32,
+                );
+            }
+        };
+        render_pass.set_stencil_reference(0);
+        multi_draw(render_pass, 0..selected_draws.start);
+        multi_draw(render_pass, selected_draws.end..draws.len());
+        render_pass.set_stencil_reference(1);
+        multi_draw(render_pass, selected_draws);
+    }
+    /// Decides which meshes the next frame draws, from the visibility of the models and, when
+    /// culling, whether their bounds intersect the camera frustum.
+    fn cull(&mut self, scene: &Scene) {
+        let frustum = Frustum::from_matrix(&scene.camera.tm());
+        let mut stats = CullingStats::default();
+        self.mesh_visibility = scene
+            .models
+            .iter()
+            .map(|model| {
+                let instance_tms = model.instance_tms();
+                model
+                    .mesh_bounds
+                    .iter()
+                    .map(|bounds| {
+                        if !model.visible {
+                            return false;
+                        }
+                        // Instances are drawn together, so all of them are unless none is seen
+                        let visible = self.effective_culling() != CullingMode::Cpu
+                            || instance_tms
+                                .iter()
+                                .any(|tm| frustum.intersects(&bounds.transform(tm)));
+                        if visible {
+                            stats.drawn += 1;
+                        } else {
+                            stats.culled += 1;
+                        }
+                        visible
+                    })
+                    .collect()
+            })
+            .collect();
+        self.culling_stats = stats;
+    }
+    /// Picks the level of detail of every mesh from the size on screen of its closest instance.
+    fn select_lods(&mut self, scene: &Scene) {
+        let previous_lods = std::mem::take(&mut self.mesh_lods);
+        let Some(resources) = &self.resources else {
+            return;
+        };
+        let mut triangles = 0;
+        for (model_idx, (model, meshes)) in scene.models.iter().zip(&resources.meshes).enumerate() {
+            let instance_tms = model.instance_tms();
+            let mut model_lods = vec![];
+            for (mesh_idx, (mesh, bounds)) in meshes.iter().zip(&model.mesh_bounds).enumerate() {
+                let screen_size = instance_tms
+                    .iter()
+                    .map(|tm| scene.camera.projected_size(&bounds.sphere.transform(tm)))
+                    .fold(0.0, f32::max);
+                let lod = self.select_lod(
+                    mesh_lod(&previous_lods, model_idx, mesh_idx),
+                    mesh.geometry.lod_count(),
+                    screen_size,
                 );
+                if self.is_mesh_visible(model_idx, mesh_idx) {
+                    triangles +=
+                        mesh.geometry.index_count(lod) as usize / 3 * mesh.instance_count as usize;
+                }
+                model_lods.push(lod);
+            }
+            self.mesh_lods.push(model_lods);
+        }
+        self.culling_stats.triangles = triangles;
+    }
+    /// Level of detail for a mesh of the given size on screen, given its level so far.
+    fn select_lod(&self, current_lod: usize, lod_count: usize, screen_size: f32) -> usize {
+        if !self.lod.enabled || lod_count <= 1 {
+            return 0;
+        }
+        // 0 at the full detail size, 1 at half of it, 2 at a quarter...
+        let level = (self.lod.full_detail_size / screen_size.max(f32::EPSILON)).log2();
+        let current_lod = current_lod.min(lod_count - 1);
+        if level < current_lod as f32 - LOD_HYSTERESIS
+            || level > (current_lod + 1) as f32 + LOD_HYSTERESIS
+        {
+            (level.max(0.0) as usize).min(lod_count - 1)
+        } else {
+            current_lod
+        }
+    }
+    fn mesh_alpha_mode(&self, model_idx: usize, mesh_idx: usize) -> AlphaMode {
+        self.mesh_alpha_modes
+            .get(model_idx)
+            .and_then(|meshes| meshes.get(mesh_idx))
+            .copied()
+            .unwrap_or_default()
+    }
+    fn is_mesh_visible(&self, model_idx: usize, mesh_idx: usize) -> bool {
+        self.mesh_visibility
+            .get(model_idx)
+            .and_then(|meshes| meshes.get(mesh_idx))
+            .copied()
+            .unwrap_or(false)
+    }
+    pub fn culling_stats(&self) -> CullingStats {
+        self.culling_stats
+    }
+    /// The culling mode in use, GPU culling falling back to the CPU when indirect draws can't
+    /// start at another instance than the first one.
+    pub fn effective_culling(&self) -> CullingMode {
+        if self.culling == CullingMode::Gpu
+            && !self
+                .device
+                .features()
+                .contains(wgpu::Features::INDIRECT_FIRST_INSTANCE)
+        {
+            return CullingMode::Cpu;
+        }
+        self.culling
+    }
+    /// Adds line segments drawn over the scene in the next frame, such as the transform gizmo.
+    pub fn set_overlay_lines(&mut self, vertices: &[LineVertex]) {
+        self.debug.on_top.extend(vertices);
+    }
+    /// Uploads the debug lines of the current frame and starts accumulating the next ones.
+    fn upload_debug_lines(&mut self) {
+        self.depth_tested_lines.upload(
+            &self.device,
+            &self.queue,
+            self.debug.depth_tested.vertices(),
+        );
+        self.on_top_lines
+            .upload(&self.device, &self.queue, self.debug.on_top.vertices());
+        self.debug.depth_tested.clear();
+        self.debug.on_top.clear();
+    }
+    fn encode_scene_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        resolve_target: Option<&wgpu::TextureView>,
+        depth_texture_view: &wgpu::TextureView,
+        scene: &Scene,
+        size: [u32; 2],
+    ) {
+        if self.effective_culling() == CullingMode::Gpu
+            && let Some(resources) = &self.resources
+        {
+            self.gpu_culling.encode(encoder, &resources.culling);
+        }
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.1,
+                        g: 0.2,
+                        b: 0.3,
+                        a: 1.0,
+                    }),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_texture_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0),
+                    store: wgpu::StoreOp::Discard,
+                }),
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        self.draw(&mut render_pass, scene, size);
+    }
+    pub fn render(&mut self, scene: &Scene) -> Result<(), wgpu::SurfaceError> {
+        self.upload_debug_lines();
+        self.prepare_pipelines(scene);
+        self.cull(scene);
+        self.select_lods(scene);
+        if let (Some(surface), Some(depth_texture_view)) = (&self.surface, &self.depth_texture_view)
+        {
+            self.update(scene);
             let output_texture = surface.get_current_texture()?;
             let view = output_texture
                 .texture
@@ -379,55 +1415,88 @@ impl<'a> Renderer<'a> {
                 .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                     label: Some("Render Encoder"),
                 });
-            {
-                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("Render Pass"),
-                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(wgpu::Color {
-                                r: 0.1,
-                                g: 0.2,
-                                b: 0.3,
-                                a: 1.0,
-                            }),
-                            store: wgpu::StoreOp::Store,
-                        },
-                    })],
-                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                        view: &depth_texture_view,
-                        depth_ops: Some(wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(1.0),
-                            store: wgpu::StoreOp::Store,
-                        }),
-                        stencil_ops: None,
-                    }),
-                    occlusion_query_set: None,
-                    timestamp_writes: None,
-                });
-                render_pass.set_pipeline(&self.render_pipeline);
-                render_pass.set_bind_group(0, Some(&bind_group), &[]);
-                for model_idx in 0..scene.models.len() {
-                    for mesh_idx in 0..scene.models[model_idx].meshes.len() {
-                        let vertex_buffer = &vertex_buffers[model_idx][mesh_idx];
-                        let index_buffer = &index_buffers[model_idx][mesh_idx];
-                        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-                        render_pass
-                            .set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                        render_pass.draw_indexed(
-                            0..scene.models[model_idx].meshes[mesh_idx].indices.len() as u32,
-                            0,
-                            0..1,
-                        );
-                    }
-                }
-            }
+            self.encode_scene_pass(
+                &mut encoder,
+                &view,
+                None,
+                depth_texture_view,
+                scene,
+                self.surface_size,
+            );
             self.queue.submit(std::iter::once(encoder.finish()));
             output_texture.present();
         };
         Ok(())
     }
+    pub fn create_render_target(&self, size: [u32; 2]) -> RenderTarget {
+        let create_color_texture = |label, sample_count, usage| {
+            self.device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width: size[0],
+                    height: size[1],
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format: self.target_format,
+                usage,
+                view_formats: &[],
+            })
+        };
+        let color_texture = create_color_texture(
+            "texture descriptor (render target)",
+            1,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        );
+        let msaa_texture_view = (self.sample_count > 1).then(|| {
+            create_color_texture(
+                "texture descriptor (render target msaa)",
+                self.sample_count,
+                wgpu::TextureUsages::RENDER_ATTACHMENT,
+            )
+            .create_view(&wgpu::TextureViewDescriptor::default())
+        });
+        RenderTarget {
+            size,
+            color_texture_view: color_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            msaa_texture_view,
+            depth_texture_view: self.create_depth_texture_view(size),
+        }
+    }
+    /// Renders the loaded scene into an offscreen target instead of the surface.
+    pub fn render_to_target(&mut self, target: &RenderTarget, scene: &Scene) {
+        self.upload_debug_lines();
+        self.prepare_pipelines(scene);
+        self.cull(scene);
+        self.select_lods(scene);
+        self.update(scene);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render Target Encoder"),
+            });
+        match &target.msaa_texture_view {
+            Some(msaa_texture_view) => self.encode_scene_pass(
+                &mut encoder,
+                msaa_texture_view,
+                Some(&target.color_texture_view),
+                &target.depth_texture_view,
+                scene,
+                target.size,
+            ),
+            None => self.encode_scene_pass(
+                &mut encoder,
+                &target.color_texture_view,
+                None,
+                &target.depth_texture_view,
+                scene,
+                target.size,
+            ),
+        }
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
 }
 
 #[repr(C)]
@@ -436,5 +1505,112 @@ pub struct VertexData {
     pub position: [f32; 3],
     pub normal: [f32; 3],
     pub uv: [f32; 2],
-    pub model_idx: u32,
+}
+impl VertexData {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 3] = [
+        // Position
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32x3,
+            offset: 0,
+            shader_location: 0,
+        },
+        // Normal
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32x3,
+            offset: std::mem::size_of::<[f32; 3]>() as u64,
+            shader_location: 1,
+        },
+        // UV
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32x2,
+            offset: std::mem::size_of::<[f32; 3 + 3]>() as u64,
+            shader_location: 2,
+        },
+    ];
+    pub fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<VertexData>() as u64,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+    /// Same attributes, advancing once per instance instead of once per vertex.
+    pub fn instance_layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            step_mode: wgpu::VertexStepMode::Instance,
+            ..Self::layout()
+        }
+    }
+}
+
+const FIRST_OBJECT_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+const FIRST_OBJECT_ATTRIBUTES: [wgpu::VertexAttribute; 1] = [wgpu::VertexAttribute {
+    format: wgpu::VertexFormat::Uint32,
+    offset: 0,
+    shader_location: 3,
+}];
+
+/// A single object index read by every instance, the buffer being bound at the draw's entry.
+fn first_object_layout() -> wgpu::VertexBufferLayout<'static> {
+    wgpu::VertexBufferLayout {
+        array_stride: 0,
+        step_mode: wgpu::VertexStepMode::Instance,
+        attributes: &FIRST_OBJECT_ATTRIBUTES,
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct ObjectData {
+    pub model_matrix: [[f32; 4]; 4],
+    pub normal_matrix: [[f32; 4]; 3],
+    pub diffuse: [f32; 4],
+    pub texture_idx: u32,
+    pub _padding: [u32; 3],
+}
+
+/// Pairs of vertex indices of every distinct edge of a triangle list, for drawing it as lines.
+fn edge_indices(indices: &[u32]) -> Vec<u32> {
+    indices
+        .chunks_exact(3)
+        .flat_map(|triangle| {
+            [
+                (triangle[0], triangle[1]),
+                (triangle[1], triangle[2]),
+                (triangle[2], triangle[0]),
+            ]
+        })
+        .map(|(a, b)| (a.min(b), a.max(b)))
+        .unique()
+        .flat_map(|(a, b)| [a, b])
+        .collect()
+}
+
+fn mesh_lod(lods: &[Vec<usize>], model_idx: usize, mesh_idx: usize) -> usize {
+    lods.get(model_idx)
+        .and_then(|meshes| meshes.get(mesh_idx))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Culling inputs of every mesh, in the same order as the meshes' draws.
+fn cull_inputs(scene: &Scene, meshes: &[Vec<MeshDraw>], lods: &[Vec<usize>]) -> Vec<CullInput> {
+    let mut inputs = vec![];
+    for (model_idx, (model, model_meshes)) in scene.models.iter().zip(meshes).enumerate() {
+        for (mesh_idx, (mesh, bounds)) in model_meshes.iter().zip(&model.mesh_bounds).enumerate() {
+            let center = bounds.sphere.center;
+            let lod = mesh_lod(lods, model_idx, mesh_idx);
+            inputs.push(CullInput {
+                sphere: [center.x, center.y, center.z, bounds.sphere.radius],
+                index_count: mesh.geometry.index_count(lod),
+                object_idx: mesh.first_object_idx,
+                instance_count: mesh.instance_count,
+                visible: model.visible as u32,
+                first_index: mesh.geometry.indices(lod).start,
+                base_vertex: mesh.geometry.base_vertex as i32,
+                _padding: [0; 2],
+            });
+        }
+    }
+    inputs
 }
@@ -0,0 +1,134 @@
+use std::f32::consts::TAU;
+
+use nalgebra::{Matrix4, Point3, Vector3};
+
+use super::LineVertex;
+use crate::bounds::{Aabb, Bounds};
+
+const SPHERE_SEGMENTS: usize = 32;
+/// Of the X, Y and Z axes, wherever they are drawn.
+pub const AXIS_COLORS: [[f32; 4]; 3] = [
+    [0.9, 0.2, 0.2, 1.0],
+    [0.2, 0.9, 0.2, 1.0],
+    [0.2, 0.4, 1.0, 1.0],
+];
+
+/// Lines accumulated during a frame, in world space.
+#[derive(Debug, Default)]
+pub struct DebugLines {
+    vertices: Vec<LineVertex>,
+}
+impl DebugLines {
+    pub fn line(&mut self, a: Point3<f32>, b: Point3<f32>, color: [f32; 4]) {
+        self.vertices.push(LineVertex {
+            position: a.into(),
+            color,
+        });
+        self.vertices.push(LineVertex {
+            position: b.into(),
+            color,
+        });
+    }
+    /// Line segments given as pairs of vertices.
+    pub fn extend(&mut self, vertices: &[LineVertex]) {
+        self.vertices.extend_from_slice(vertices);
+    }
+    pub fn arrow(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4]) {
+        self.line(from, to, color);
+        let direction = to - from;
+        let length = direction.norm();
+        if length <= f32::EPSILON {
+            return;
+        }
+        let direction = direction / length;
+        // Any vector not parallel to the arrow gives the plane of the head
+        let up = if direction.y.abs() < 0.9 {
+            Vector3::y()
+        } else {
+            Vector3::x()
+        };
+        let side = direction.cross(&up).normalize() * length * 0.1;
+        let back = to - direction * length * 0.2;
+        self.line(to, back + side, color);
+        self.line(to, back - side, color);
+    }
+    /// Axis-aligned box between two opposite corners.
+    pub fn aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4]) {
+        self.box_edges(&Aabb { min, max }.corners(), color);
+    }
+    /// One circle around each axis.
+    pub fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4]) {
+        for axis in 0..3 {
+            let point = |segment: usize| {
+                let angle = segment as f32 * TAU / SPHERE_SEGMENTS as f32;
+                let mut offset = Vector3::zeros();
+                offset[(axis + 1) % 3] = angle.cos() * radius;
+                offset[(axis + 2) % 3] = angle.sin() * radius;
+                center + offset
+            };
+            for segment in 0..SPHERE_SEGMENTS {
+                self.line(point(segment), point(segment + 1), color);
+            }
+        }
+    }
+    /// Box and sphere of bounding volumes, the sphere being fainter.
+    pub fn bounds(&mut self, bounds: &Bounds, color: [f32; 4]) {
+        self.aabb(bounds.aabb.min, bounds.aabb.max, color);
+        let [r, g, b, a] = color;
+        self.sphere(
+            bounds.sphere.center,
+            bounds.sphere.radius,
+            [r, g, b, a * 0.3],
+        );
+    }
+    /// X, Y and Z axes of a transform, in red, green and blue.
+    pub fn axes(&mut self, tm: &Matrix4<f32>, length: f32) {
+        let origin = tm.transform_point(&Point3::origin());
+        for (axis, color) in AXIS_COLORS.into_iter().enumerate() {
+            let mut tip = Point3::origin();
+            tip[axis] = length;
+            self.line(origin, tm.transform_point(&tip), color);
+        }
+    }
+    /// Edges of the volume seen through a view projection matrix, such as `Camera::tm`.
+    pub fn frustum(&mut self, view_projection: &Matrix4<f32>, color: [f32; 4]) {
+        let Some(inverse_tm) = view_projection.try_inverse() else {
+            return;
+        };
+        // nalgebra's perspective maps the near plane to a depth of -1
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            inverse_tm.transform_point(&Point3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+            ))
+        });
+        self.box_edges(&corners, color);
+    }
+    /// Edges of a box whose corner `i` has its x, y and z at the max side for bits 0, 1 and 2.
+    fn box_edges(&mut self, corners: &[Point3<f32>; 8], color: [f32; 4]) {
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.line(corners[i], corners[i | bit], color);
+                }
+            }
+        }
+    }
+    pub fn vertices(&self) -> &[LineVertex] {
+        &self.vertices
+    }
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+}
+
+/// Immediate mode debug drawing: shapes are added during a frame, drawn by the next render and
+/// then discarded.
+#[derive(Debug, Default)]
+pub struct DebugDraw {
+    /// Hidden by the geometry in front of them.
+    pub depth_tested: DebugLines,
+    /// Always visible, over the whole scene.
+    pub on_top: DebugLines,
+}
@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+
+use eframe::wgpu;
+
+use super::VertexData;
+use crate::mesh_simplifier::MAX_LOD_LEVELS;
+
+/// Capacity of the buffers of an empty pool, in vertices or indices.
+const INITIAL_CAPACITY: u32 = 1 << 16;
+/// Index lists of a mesh, the mesh itself and its simplified versions.
+pub const MAX_LODS: usize = MAX_LOD_LEVELS + 1;
+
+/// Identifies an allocation of the pool, valid until it is freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GeometryHandle(u32);
+
+/// Where the vertices and indices of a mesh are in the pool's buffers. The indices are relative
+/// to the mesh's vertices, so drawing them takes `base_vertex`. The index list of every level of
+/// detail follows the previous one's, all indexing the same vertices, followed by the edge
+/// indices of the mesh, if any. They are 16-bit whenever the mesh has few enough vertices, in
+/// which case every list is padded to a multiple of 2 to keep the copies 4-byte aligned.
+#[derive(Debug, Clone, Copy)]
+pub struct GeometryRange {
+    pub base_vertex: u32,
+    pub vertex_count: u32,
+    pub index_format: wgpu::IndexFormat,
+    pub first_index: u32,
+    lod_index_counts: [u32; MAX_LODS],
+    lod_count: usize,
+    pub edge_index_count: u32,
+}
+impl GeometryRange {
+    /// Levels of detail, at least the mesh itself.
+    pub fn lod_count(&self) -> usize {
+        self.lod_count
+    }
+    pub fn index_count(&self, lod: usize) -> u32 {
+        self.lod_index_counts[lod]
+    }
+    pub fn indices(&self, lod: usize) -> std::ops::Range<u32> {
+        let first_index = self.first_index + self.padded_index_count(lod);
+        first_index..first_index + self.lod_index_counts[lod]
+    }
+    pub fn edge_indices(&self) -> std::ops::Range<u32> {
+        let first_edge_index = self.first_index + self.padded_index_count(self.lod_count);
+        first_edge_index..first_edge_index + self.edge_index_count
+    }
+    /// Indices of the levels before `lod`, padding included.
+    fn padded_index_count(&self, lod: usize) -> u32 {
+        self.lod_index_counts[..lod]
+            .iter()
+            .map(|&index_count| self.padded(index_count))
+            .sum()
+    }
+    fn padded(&self, index_count: u32) -> u32 {
+        match self.index_format {
+            wgpu::IndexFormat::Uint16 => index_count.next_multiple_of(2),
+            wgpu::IndexFormat::Uint32 => index_count,
+        }
+    }
+    fn total_index_count(&self) -> u32 {
+        self.padded_index_count(self.lod_count) + self.padded(self.edge_index_count)
+    }
+}
+
+/// A buffer whose elements are allocated one after the other.
+struct Arena {
+    label: &'static str,
+    usage: wgpu::BufferUsages,
+    element_size: u64,
+    buffer: wgpu::Buffer,
+    capacity: u32,
+    len: u32,
+}
+impl Arena {
+    fn new(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsages,
+        element_size: u64,
+        capacity: u32,
+    ) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: capacity as u64 * element_size,
+            usage: usage | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        Self {
+            label,
+            usage,
+            element_size,
+            buffer,
+            capacity,
+            len: 0,
+        }
+    }
+    /// An empty arena of the same kind, with room for at least `capacity` elements.
+    fn with_capacity(&self, device: &wgpu::Device, capacity: u32) -> Self {
+        Self::new(
+            device,
+            self.label,
+            self.usage,
+            self.element_size,
+            capacity.max(INITIAL_CAPACITY),
+        )
+    }
+    /// Makes room for `additional` elements after the last one, at least doubling the buffer
+    /// when it is full. Returns whether copies into a new buffer were recorded.
+    fn reserve(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        additional: u32,
+    ) -> bool {
+        let needed = self.len + additional;
+        if needed <= self.capacity {
+            return false;
+        }
+        let mut grown = self.with_capacity(device, needed.max(self.capacity * 2));
+        grown.append_copy(encoder, self, 0, self.len);
+        *self = grown;
+        true
+    }
+    /// Copies elements of another arena after the last one, returning where they start.
+    fn append_copy(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        source: &Arena,
+        first: u32,
+        count: u32,
+    ) -> u32 {
+        let start = self.len;
+        if count > 0 {
+            encoder.copy_buffer_to_buffer(
+                &source.buffer,
+                first as u64 * self.element_size,
+                &self.buffer,
+                start as u64 * self.element_size,
+                count as u64 * self.element_size,
+            );
+        }
+        self.len += count;
+        start
+    }
+    /// Writes elements after the last one, which must have been reserved, returning where they
+    /// start.
+    fn append(&mut self, queue: &wgpu::Queue, data: &[u8]) -> u32 {
+        let start = self.len;
+        queue.write_buffer(&self.buffer, start as u64 * self.element_size, data);
+        self.len += (data.len() as u64 / self.element_size) as u32;
+        start
+    }
+    fn used_bytes(&self) -> u64 {
+        self.len as u64 * self.element_size
+    }
+}
+
+/// The geometry of every mesh, sub-allocated from a single vertex buffer and an index buffer per
+/// index format so that the draws share the same bindings. Allocations are appended after the
+/// last one, freeing leaves a hole until the pool is compacted, and the buffers grow as needed.
+/// Both happen on the GPU, copying into new buffers, so no copy of the geometry is kept on the
+/// CPU.
+pub struct GeometryPool {
+    vertices: Arena,
+    indices_u16: Arena,
+    indices_u32: Arena,
+    /// Live allocations, handles being given in increasing order so that they are sorted by
+    /// position in the buffers.
+    ranges: BTreeMap<GeometryHandle, GeometryRange>,
+    next_handle: u32,
+}
+impl GeometryPool {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            vertices: Arena::new(
+                device,
+                "buffer descriptor (pool vertices)",
+                wgpu::BufferUsages::VERTEX,
+                std::mem::size_of::<VertexData>() as u64,
+                INITIAL_CAPACITY,
+            ),
+            indices_u16: Arena::new(
+                device,
+                "buffer descriptor (pool 16-bit indices)",
+                wgpu::BufferUsages::INDEX,
+                std::mem::size_of::<u16>() as u64,
+                INITIAL_CAPACITY,
+            ),
+            indices_u32: Arena::new(
+                device,
+                "buffer descriptor (pool 32-bit indices)",
+                wgpu::BufferUsages::INDEX,
+                std::mem::size_of::<u32>() as u64,
+                INITIAL_CAPACITY,
+            ),
+            ranges: BTreeMap::new(),
+            next_handle: 0,
+        }
+    }
+    /// Uploads a mesh with the index lists of its levels of detail, the mesh itself first, and
+    /// the pairs of indices of its edges when lines can't be rasterized from its triangles.
+    pub fn allocate(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        vertices: &[VertexData],
+        lod_indices: &[&[u32]],
+        edge_indices: &[u32],
+    ) -> GeometryHandle {
+        assert!((1..=MAX_LODS).contains(&lod_indices.len()));
+        let index_format = index_format(vertices.len());
+        let index_data = lod_indices
+            .iter()
+            .chain(std::iter::once(&edge_indices))
+            .flat_map(|indices| index_bytes(indices, index_format))
+            .collect::<Vec<_>>();
+        let index_arena = match index_format {
+            wgpu::IndexFormat::Uint16 => &mut self.indices_u16,
+            wgpu::IndexFormat::Uint32 => &mut self.indices_u32,
+        };
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Geometry Pool Growth Encoder"),
+        });
+        let grown_vertices = self
+            .vertices
+            .reserve(device, &mut encoder, vertices.len() as u32);
+        let grown_indices = index_arena.reserve(
+            device,
+            &mut encoder,
+            (index_data.len() as u64 / index_arena.element_size) as u32,
+        );
+        if grown_vertices || grown_indices {
+            // Writes queued before this point land in the old buffers before they are copied
+            queue.submit(std::iter::once(encoder.finish()));
+        }
+        let mut lod_index_counts = [0; MAX_LODS];
+        for (index_count, indices) in lod_index_counts.iter_mut().zip(lod_indices) {
+            *index_count = indices.len() as u32;
+        }
+        let range = GeometryRange {
+            base_vertex: self.vertices.append(queue, bytemuck::cast_slice(vertices)),
+            vertex_count: vertices.len() as u32,
+            index_format,
+            first_index: index_arena.append(queue, &index_data),
+            lod_index_counts,
+            lod_count: lod_indices.len(),
+            edge_index_count: edge_indices.len() as u32,
+        };
+        let handle = GeometryHandle(self.next_handle);
+        self.next_handle += 1;
+        self.ranges.insert(handle, range);
+        handle
+    }
+    /// Forgets an allocation, its space being reclaimed by the next `compact`.
+    pub fn free(&mut self, handle: GeometryHandle) {
+        self.ranges.remove(&handle);
+    }
+    /// Forgets every allocation at once, keeping the buffers.
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+        self.vertices.len = 0;
+        self.indices_u16.len = 0;
+        self.indices_u32.len = 0;
+    }
+    pub fn range(&self, handle: GeometryHandle) -> GeometryRange {
+        self.ranges[&handle]
+    }
+    /// Bytes of the buffers holding geometry, holes left by freed allocations included.
+    pub fn used_bytes(&self) -> u64 {
+        self.vertices.used_bytes() + self.indices_u16.used_bytes() + self.indices_u32.used_bytes()
+    }
+    /// Moves the live allocations next to each other, into buffers just large enough for them.
+    /// Their ranges change, so the draws using them must be recorded again.
+    pub fn compact(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let vertex_count = self.ranges.values().map(|range| range.vertex_count).sum();
+        let index_count = |index_format| {
+            self.ranges
+                .values()
+                .filter(|range| range.index_format == index_format)
+                .map(GeometryRange::total_index_count)
+                .sum()
+        };
+        let mut vertices = self.vertices.with_capacity(device, vertex_count);
+        let mut indices_u16 = self
+            .indices_u16
+            .with_capacity(device, index_count(wgpu::IndexFormat::Uint16));
+        let mut indices_u32 = self
+            .indices_u32
+            .with_capacity(device, index_count(wgpu::IndexFormat::Uint32));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Geometry Pool Compaction Encoder"),
+        });
+        for range in self.ranges.values_mut() {
+            range.base_vertex = vertices.append_copy(
+                &mut encoder,
+                &self.vertices,
+                range.base_vertex,
+                range.vertex_count,
+            );
+            let (old_indices, new_indices) = match range.index_format {
+                wgpu::IndexFormat::Uint16 => (&self.indices_u16, &mut indices_u16),
+                wgpu::IndexFormat::Uint32 => (&self.indices_u32, &mut indices_u32),
+            };
+            range.first_index = new_indices.append_copy(
+                &mut encoder,
+                old_indices,
+                range.first_index,
+                range.total_index_count(),
+            );
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+        self.vertices = vertices;
+        self.indices_u16 = indices_u16;
+        self.indices_u32 = indices_u32;
+    }
+    /// Binds the shared vertex buffer, for every mesh of the pool to be drawn from its range.
+    pub fn bind_vertices(&self, render_pass: &mut wgpu::RenderPass<'_>) {
+        render_pass.set_vertex_buffer(0, self.vertices.buffer.slice(..));
+    }
+    /// Binds the index buffer of the meshes whose indices have the given format.
+    pub fn bind_indices(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        index_format: wgpu::IndexFormat,
+    ) {
+        let arena = match index_format {
+            wgpu::IndexFormat::Uint16 => &self.indices_u16,
+            wgpu::IndexFormat::Uint32 => &self.indices_u32,
+        };
+        render_pass.set_index_buffer(arena.buffer.slice(..), index_format);
+    }
+}
+
+/// Smallest index format that can index all the vertices.
+fn index_format(vertex_count: usize) -> wgpu::IndexFormat {
+    if vertex_count <= 1 << 16 {
+        wgpu::IndexFormat::Uint16
+    } else {
+        wgpu::IndexFormat::Uint32
+    }
+}
+
+/// Indices in the given format, padded to a multiple of 4 bytes.
+fn index_bytes(indices: &[u32], index_format: wgpu::IndexFormat) -> Vec<u8> {
+    match index_format {
+        wgpu::IndexFormat::Uint16 => {
+            let mut indices = indices
+                .iter()
+                .map(|&index| index as u16)
+                .collect::<Vec<_>>();
+            if indices.len() % 2 == 1 {
+                indices.push(0);
+            }
+            bytemuck::cast_slice(&indices).to_vec()
+        }
+        wgpu::IndexFormat::Uint32 => bytemuck::cast_slice(indices).to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn teapot_indices_take_half_the_memory() {
+        let (models, _) = tobj::load_obj(
+            "./models/teapot.obj",
+            &tobj::LoadOptions {
+                single_index: true,
+                triangulate: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mesh = &models[0].mesh;
+        let index_format = index_format(mesh.positions.len() / 3);
+        assert_eq!(index_format, wgpu::IndexFormat::Uint16);
+        let index_count = mesh.indices.len().next_multiple_of(2);
+        assert_eq!(
+            index_bytes(&mesh.indices, index_format).len(),
+            index_count * 2
+        );
+        assert_eq!(
+            index_bytes(&mesh.indices, wgpu::IndexFormat::Uint32).len(),
+            mesh.indices.len() * 4
+        );
+    }
+
+    #[test]
+    fn index_format_fits_the_vertices() {
+        assert_eq!(index_format(1 << 16), wgpu::IndexFormat::Uint16);
+        assert_eq!(index_format((1 << 16) + 1), wgpu::IndexFormat::Uint32);
+    }
+}
@@ -0,0 +1,212 @@
+use bytemuck::{Pod, Zeroable};
+use eframe::wgpu::{self, util::DeviceExt};
+
+use crate::bounds::Frustum;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// What the culling shader needs to know about a draw, one per mesh.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct CullInput {
+    /// Bounding sphere center and radius, in model space.
+    pub sphere: [f32; 4],
+    pub index_count: u32,
+    /// Object of the first instance, whose model matrix transforms the sphere.
+    pub object_idx: u32,
+    /// Instances are drawn together, unless none of them is in the frustum.
+    pub instance_count: u32,
+    /// 0 when the model is hidden.
+    pub visible: u32,
+    /// Range of the mesh in the geometry pool.
+    pub first_index: u32,
+    pub base_vertex: i32,
+    pub _padding: [u32; 2],
+}
+
+/// Buffers of the culling shader for the loaded scene.
+pub struct CullingResources {
+    input_buffer: wgpu::Buffer,
+    /// One `DrawIndexedIndirectArgs` per draw, written by the culling shader.
+    pub indirect_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    draw_count: u32,
+}
+
+/// Frustum culling on the GPU: a compute shader tests the bounds of every draw and writes its
+/// indirect draw arguments, with an instance count of 0 for the culled ones.
+pub struct GpuCulling {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    frustum_buffer: wgpu::Buffer,
+}
+impl GpuCulling {
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+    ) -> Self {
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("bind group layout (culling)"),
+            entries: &[
+                // Objects of the scene
+                storage_entry(0, true),
+                // Draws to cull
+                storage_entry(1, true),
+                // Frustum planes
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // Indirect draw arguments
+                storage_entry(3, false),
+            ],
+        });
+        let pipeline = create_pipeline(device, shader_module, pipeline_cache, &bind_group_layout);
+        let frustum_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("buffer descriptor (frustum)"),
+            size: std::mem::size_of::<[[f32; 4]; 6]>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            pipeline,
+            bind_group_layout,
+            frustum_buffer,
+        }
+    }
+    /// Recreates the pipeline from another shader module.
+    pub fn rebuild_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+    ) {
+        self.pipeline = create_pipeline(
+            device,
+            shader_module,
+            pipeline_cache,
+            &self.bind_group_layout,
+        );
+    }
+    pub fn create_resources(
+        &self,
+        device: &wgpu::Device,
+        objects_buffer: &wgpu::Buffer,
+        inputs: &[CullInput],
+    ) -> CullingResources {
+        let draw_count = inputs.len() as u32;
+        // Storage buffers can't be empty
+        let placeholder = [CullInput::zeroed()];
+        let inputs = if inputs.is_empty() {
+            &placeholder
+        } else {
+            inputs
+        };
+        let input_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("buffer init descriptor (cull inputs)"),
+            contents: bytemuck::cast_slice(inputs),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+        let indirect_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("buffer descriptor (indirect draws)"),
+            size: (inputs.len() * std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>())
+                as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("bind group descriptor (culling)"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: objects_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: input_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.frustum_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: indirect_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        CullingResources {
+            input_buffer,
+            indirect_buffer,
+            bind_group,
+            draw_count,
+        }
+    }
+    /// Uploads the frustum and the draws of the current frame.
+    pub fn update(
+        &self,
+        queue: &wgpu::Queue,
+        resources: &CullingResources,
+        frustum: &Frustum,
+        inputs: &[CullInput],
+    ) {
+        queue.write_buffer(
+            &self.frustum_buffer,
+            0,
+            bytemuck::cast_slice(&frustum.planes().map(<[f32; 4]>::from)),
+        );
+        queue.write_buffer(&resources.input_buffer, 0, bytemuck::cast_slice(inputs));
+    }
+    /// Records the culling pass, which must run before the render pass drawing indirectly.
+    pub fn encode(&self, encoder: &mut wgpu::CommandEncoder, resources: &CullingResources) {
+        if resources.draw_count == 0 {
+            return;
+        }
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Culling Pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, Some(&resources.bind_group), &[]);
+        compute_pass.dispatch_workgroups(resources.draw_count.div_ceil(WORKGROUP_SIZE), 1, 1);
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    pipeline_cache: Option<&wgpu::PipelineCache>,
+    bind_group_layout: &wgpu::BindGroupLayout,
+) -> wgpu::ComputePipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor (culling)"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("compute pipeline descriptor (culling)"),
+        layout: Some(&pipeline_layout),
+        module: shader_module,
+        entry_point: Some("cs_cull"),
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+        cache: pipeline_cache,
+    })
+}
@@ -0,0 +1,212 @@
+use eframe::wgpu;
+
+use super::DEPTH_FORMAT;
+
+/// Infinite grid on the XZ plane, drawn by a full-screen shader, and an indicator of the world
+/// axes in the bottom left corner of the viewport, following the camera rotation.
+pub struct GridPass {
+    pub show_grid: bool,
+    pub show_axes: bool,
+    grid_pipeline: wgpu::RenderPipeline,
+    axes_pipeline: wgpu::RenderPipeline,
+}
+impl GridPass {
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+        scene_bind_group_layout: &wgpu::BindGroupLayout,
+        target_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        Self {
+            show_grid: true,
+            show_axes: true,
+            grid_pipeline: create_grid_pipeline(
+                device,
+                shader_module,
+                pipeline_cache,
+                scene_bind_group_layout,
+                target_format,
+                sample_count,
+            ),
+            axes_pipeline: create_axes_pipeline(
+                device,
+                shader_module,
+                pipeline_cache,
+                scene_bind_group_layout,
+                target_format,
+                sample_count,
+            ),
+        }
+    }
+    /// Recreates the pipelines after the scene bind group layout changed.
+    pub fn rebuild_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+        scene_bind_group_layout: &wgpu::BindGroupLayout,
+        target_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) {
+        *self = Self {
+            show_grid: self.show_grid,
+            show_axes: self.show_axes,
+            ..Self::new(
+                device,
+                shader_module,
+                pipeline_cache,
+                scene_bind_group_layout,
+                target_format,
+                sample_count,
+            )
+        };
+    }
+    /// Draws the grid, depth tested against the scene already drawn.
+    pub fn draw_grid(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        scene_bind_group: &wgpu::BindGroup,
+    ) {
+        if !self.show_grid {
+            return;
+        }
+        render_pass.set_pipeline(&self.grid_pipeline);
+        render_pass.set_bind_group(0, Some(scene_bind_group), &[]);
+        // A single triangle covering the viewport
+        render_pass.draw(0..3, 0..1);
+    }
+    /// Draws the axes indicator over everything else.
+    pub fn draw_axes(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        scene_bind_group: &wgpu::BindGroup,
+    ) {
+        if !self.show_axes {
+            return;
+        }
+        render_pass.set_pipeline(&self.axes_pipeline);
+        render_pass.set_bind_group(0, Some(scene_bind_group), &[]);
+        render_pass.draw(0..6, 0..1);
+    }
+}
+
+fn create_grid_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    pipeline_cache: Option<&wgpu::PipelineCache>,
+    scene_bind_group_layout: &wgpu::BindGroupLayout,
+    target_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor (grid)"),
+        bind_group_layouts: &[scene_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("render pipeline descriptor (grid)"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader_module,
+            entry_point: Some("vs_grid"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader_module,
+            entry_point: Some("fs_grid"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: target_format,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            unclipped_depth: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+        },
+        // The fragment shader writes the depth of the plane, but the grid being transparent it
+        // must not hide what is drawn after it
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: pipeline_cache,
+    })
+}
+
+fn create_axes_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    pipeline_cache: Option<&wgpu::PipelineCache>,
+    scene_bind_group_layout: &wgpu::BindGroupLayout,
+    target_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor (axes)"),
+        bind_group_layouts: &[scene_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("render pipeline descriptor (axes)"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader_module,
+            entry_point: Some("vs_axes_indicator"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader_module,
+            entry_point: Some("fs_line"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: target_format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::LineList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            unclipped_depth: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+        },
+        // Always on top of the scene
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: pipeline_cache,
+    })
+}
@@ -0,0 +1,179 @@
+use bytemuck::{Pod, Zeroable};
+use eframe::wgpu;
+
+use super::DEPTH_FORMAT;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct LineVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 4],
+}
+impl LineVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] = [
+        // Position
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32x3,
+            offset: 0,
+            shader_location: 0,
+        },
+        // Color
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32x4,
+            offset: std::mem::size_of::<[f32; 3]>() as u64,
+            shader_location: 1,
+        },
+    ];
+    pub fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<LineVertex>() as u64,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// World space line segments, two vertices per segment, either depth tested against the scene
+/// or drawn over it.
+pub struct LinePass {
+    depth_tested: bool,
+    pipeline: wgpu::RenderPipeline,
+    vertex_buffer: wgpu::Buffer,
+    vertex_count: u32,
+}
+impl LinePass {
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+        scene_bind_group_layout: &wgpu::BindGroupLayout,
+        target_format: wgpu::TextureFormat,
+        sample_count: u32,
+        depth_tested: bool,
+    ) -> Self {
+        Self {
+            depth_tested,
+            pipeline: create_pipeline(
+                device,
+                shader_module,
+                pipeline_cache,
+                scene_bind_group_layout,
+                target_format,
+                sample_count,
+                depth_tested,
+            ),
+            vertex_buffer: create_vertex_buffer(device, 1024),
+            vertex_count: 0,
+        }
+    }
+    /// Recreates the pipeline after the scene bind group layout changed.
+    pub fn rebuild_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+        scene_bind_group_layout: &wgpu::BindGroupLayout,
+        target_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) {
+        self.pipeline = create_pipeline(
+            device,
+            shader_module,
+            pipeline_cache,
+            scene_bind_group_layout,
+            target_format,
+            sample_count,
+            self.depth_tested,
+        );
+    }
+    /// Replaces the lines to draw, growing the vertex buffer when needed.
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, vertices: &[LineVertex]) {
+        let size = std::mem::size_of_val(vertices) as u64;
+        if size > self.vertex_buffer.size() {
+            self.vertex_buffer = create_vertex_buffer(device, vertices.len().next_power_of_two());
+        }
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(vertices));
+        self.vertex_count = vertices.len() as u32;
+    }
+    pub fn draw(&self, render_pass: &mut wgpu::RenderPass<'_>, scene_bind_group: &wgpu::BindGroup) {
+        if self.vertex_count == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, Some(scene_bind_group), &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.draw(0..self.vertex_count, 0..1);
+    }
+}
+
+fn create_vertex_buffer(device: &wgpu::Device, vertices_count: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("buffer descriptor (lines)"),
+        size: (vertices_count * std::mem::size_of::<LineVertex>()) as u64,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    pipeline_cache: Option<&wgpu::PipelineCache>,
+    scene_bind_group_layout: &wgpu::BindGroupLayout,
+    target_format: wgpu::TextureFormat,
+    sample_count: u32,
+    depth_tested: bool,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor (lines)"),
+        bind_group_layouts: &[scene_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("render pipeline descriptor (lines)"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader_module,
+            entry_point: Some("vs_line"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            buffers: &[LineVertex::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader_module,
+            entry_point: Some("fs_line"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: target_format,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::LineList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            unclipped_depth: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: if depth_tested {
+                wgpu::CompareFunction::LessEqual
+            } else {
+                wgpu::CompareFunction::Always
+            },
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: pipeline_cache,
+    })
+}
@@ -0,0 +1,207 @@
+use bytemuck::{Pod, Zeroable};
+use eframe::wgpu::{self, util::DeviceExt};
+
+use super::{DEPTH_FORMAT, GeometryPool, MeshDraw, VertexData};
+
+/// Number of screen space offsets the selected model is redrawn at to form its outline.
+pub const OUTLINE_DIRECTIONS: u32 = 8;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+struct OutlineData {
+    color: [f32; 4],
+    viewport_size: [f32; 2],
+    width: f32,
+    _padding: f32,
+}
+
+/// Outline around the selected model. The model is redrawn offset in several directions
+/// wherever the stencil doesn't hold its silhouette, which works without vertex normals.
+pub struct OutlinePass {
+    pub color: [f32; 4],
+    /// Width in pixels
+    pub width: f32,
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+impl OutlinePass {
+    pub fn new(
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+        scene_bind_group_layout: &wgpu::BindGroupLayout,
+        target_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("bind group layout (outline)"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("buffer init descriptor (outline)"),
+            contents: bytemuck::bytes_of(&OutlineData::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("bind group descriptor (outline)"),
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        });
+        let pipeline = create_pipeline(
+            device,
+            shader_module,
+            pipeline_cache,
+            scene_bind_group_layout,
+            &bind_group_layout,
+            target_format,
+            sample_count,
+        );
+        Self {
+            color: [1.0, 0.6, 0.0, 1.0],
+            width: 3.0,
+            pipeline,
+            bind_group_layout,
+            uniform_buffer,
+            bind_group,
+        }
+    }
+    /// Recreates the pipeline after the scene bind group layout changed.
+    pub fn rebuild_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        shader_module: &wgpu::ShaderModule,
+        pipeline_cache: Option<&wgpu::PipelineCache>,
+        scene_bind_group_layout: &wgpu::BindGroupLayout,
+        target_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) {
+        self.pipeline = create_pipeline(
+            device,
+            shader_module,
+            pipeline_cache,
+            scene_bind_group_layout,
+            &self.bind_group_layout,
+            target_format,
+            sample_count,
+        );
+    }
+    /// Draws the outline of the given meshes, whose silhouette must already be in the stencil.
+    pub fn draw<'a>(
+        &self,
+        queue: &wgpu::Queue,
+        render_pass: &mut wgpu::RenderPass<'_>,
+        scene_bind_group: &wgpu::BindGroup,
+        geometry: &GeometryPool,
+        // Meshes with their level of detail
+        meshes: impl IntoIterator<Item = (&'a MeshDraw, usize)>,
+        viewport_size: [u32; 2],
+    ) {
+        let outline_data = OutlineData {
+            color: self.color,
+            viewport_size: [viewport_size[0] as f32, viewport_size[1] as f32],
+            width: self.width,
+            _padding: 0.0,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&outline_data));
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, Some(scene_bind_group), &[]);
+        render_pass.set_bind_group(1, Some(&self.bind_group), &[]);
+        render_pass.set_stencil_reference(1);
+        geometry.bind_vertices(render_pass);
+        for (mesh, lod) in meshes {
+            geometry.bind_indices(render_pass, mesh.geometry.index_format);
+            // The instance index divided by the directions is the object index
+            let first_instance = OUTLINE_DIRECTIONS * mesh.first_object_idx;
+            render_pass.draw_indexed(
+                mesh.geometry.indices(lod),
+                mesh.geometry.base_vertex as i32,
+                first_instance..first_instance + OUTLINE_DIRECTIONS * mesh.instance_count,
+            );
+        }
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    pipeline_cache: Option<&wgpu::PipelineCache>,
+    scene_bind_group_layout: &wgpu::BindGroupLayout,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    target_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor (outline)"),
+        bind_group_layouts: &[scene_bind_group_layout, bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let outside_silhouette = wgpu::StencilFaceState {
+        compare: wgpu::CompareFunction::NotEqual,
+        fail_op: wgpu::StencilOperation::Keep,
+        depth_fail_op: wgpu::StencilOperation::Keep,
+        pass_op: wgpu::StencilOperation::Keep,
+    };
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("render pipeline descriptor (outline)"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader_module,
+            entry_point: Some("vs_outline"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            buffers: &[VertexData::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader_module,
+            entry_point: Some("fs_outline"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: target_format,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            unclipped_depth: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+        },
+        // Drawn on top of everything, only outside of the selected model's silhouette
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: wgpu::StencilState {
+                front: outside_silhouette,
+                back: outside_silhouette,
+                read_mask: 0xff,
+                write_mask: 0x00,
+            },
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache: pipeline_cache,
+    })
+}
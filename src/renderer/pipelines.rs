@@ -0,0 +1,356 @@
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+use eframe::wgpu;
+use log::{info, warn};
+
+use super::{
+    DEPTH_FORMAT, SILHOUETTE_STENCIL_FACE, ShaderPermutation, VertexData, first_object_layout,
+};
+
+/// How a scene pipeline draws the meshes, which decides its entry points, vertex layout and
+/// primitives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScenePass {
+    Shaded,
+    Wireframe,
+    Normals,
+}
+
+/// Everything a scene pipeline is created from other than the bind group layout, which all of
+/// them share and only changes along with the scene's texture count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub permutation: ShaderPermutation,
+    pub pass: ScenePass,
+    pub target_format: wgpu::TextureFormat,
+    pub sample_count: u32,
+    pub cull_mode: Option<wgpu::Face>,
+    pub blend: Option<wgpu::BlendState>,
+    pub depth_write: bool,
+}
+
+/// Scene pipelines by key, created when they are first needed and reused afterwards. Backends
+/// supporting it also keep the compiled pipelines in a `wgpu::PipelineCache`, saved to disk so
+/// that the next runs start faster.
+pub struct PipelineRegistry {
+    /// `None` for the keys whose pipelines failed to compile.
+    pipelines: HashMap<PipelineKey, Option<wgpu::RenderPipeline>>,
+    cache: Option<wgpu::PipelineCache>,
+    cache_path: Option<PathBuf>,
+}
+impl PipelineRegistry {
+    /// Loads the pipeline cache of the adapter saved by a previous run, when the device has the
+    /// `PIPELINE_CACHE` feature.
+    pub fn new(device: &wgpu::Device, adapter_info: &wgpu::AdapterInfo) -> Self {
+        let cache_path = device
+            .features()
+            .contains(wgpu::Features::PIPELINE_CACHE)
+            .then(|| wgpu::util::pipeline_cache_key(adapter_info))
+            .flatten()
+            .map(|file_name| cache_dir().join(env!("CARGO_PKG_NAME")).join(file_name));
+        let cache = cache_path.as_ref().map(|cache_path| {
+            let data = fs::read(cache_path).ok();
+            if let Some(data) = &data {
+                info!(
+                    "Pipeline cache: {} KiB from {}",
+                    data.len().div_ceil(1024),
+                    cache_path.display()
+                );
+            }
+            // SAFETY: the data was returned by `get_data` of a cache of an adapter with the same
+            // cache key, which the file name is. Data from another driver version is ignored
+            // thanks to the fallback.
+            unsafe {
+                device.create_pipeline_cache(&wgpu::PipelineCacheDescriptor {
+                    label: Some("pipeline cache descriptor"),
+                    data: data.as_deref(),
+                    fallback: true,
+                })
+            }
+        });
+        Self {
+            pipelines: HashMap::new(),
+            cache,
+            cache_path,
+        }
+    }
+    /// The pipeline of the key, unless it wasn't created yet or failed to compile.
+    pub fn get(&self, key: &PipelineKey) -> Option<&wgpu::RenderPipeline> {
+        self.pipelines.get(key)?.as_ref()
+    }
+    /// Whether the pipeline of the key was created, even if it failed to compile.
+    pub fn contains(&self, key: &PipelineKey) -> bool {
+        self.pipelines.contains_key(key)
+    }
+    pub fn insert(&mut self, key: PipelineKey, pipeline: Option<wgpu::RenderPipeline>) {
+        self.pipelines.insert(key, pipeline);
+    }
+    /// Removes all the pipelines, returning the keys of those that compiled to create them again.
+    pub fn clear(&mut self) -> Vec<PipelineKey> {
+        self.pipelines
+            .drain()
+            .filter_map(|(key, pipeline)| pipeline.map(|_| key))
+            .collect()
+    }
+    /// For the pipelines created outside of the registry to use it as well.
+    pub fn cache(&self) -> Option<&wgpu::PipelineCache> {
+        self.cache.as_ref()
+    }
+    /// Writes the pipeline cache to disk, for the next runs.
+    pub fn save_cache(&self) {
+        let (Some(cache), Some(cache_path)) = (&self.cache, &self.cache_path) else {
+            return;
+        };
+        let Some(data) = cache.get_data() else {
+            return;
+        };
+        // Written next to the file and then renamed, so that a run exiting halfway through
+        // doesn't leave a truncated cache
+        let temporary_path = cache_path.with_extension("tmp");
+        let result = fs::create_dir_all(cache_path.parent().unwrap())
+            .and_then(|()| fs::write(&temporary_path, &data))
+            .and_then(|()| fs::rename(&temporary_path, cache_path));
+        match result {
+            Ok(()) => info!(
+                "Saved the pipeline cache, {} KiB, to {}",
+                data.len().div_ceil(1024),
+                cache_path.display()
+            ),
+            Err(save_error) => warn!(
+                "Saving the pipeline cache to {} failed: {save_error}",
+                cache_path.display()
+            ),
+        }
+    }
+}
+
+/// Per-user cache directory of the platform, which unlike the temporary directory survives
+/// reboots. The temporary directory is only used when the environment doesn't tell where it is.
+fn cache_dir() -> PathBuf {
+    let cache_dir = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Caches"))
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            // Relative paths are invalid according to the XDG specification
+            .filter(|cache_home| cache_home.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+    cache_dir.unwrap_or_else(env::temp_dir)
+}
+
+/// Creates the pipeline of a key from the scene shader module of its permutation.
+pub fn create_scene_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    key: &PipelineKey,
+    cache: Option<&wgpu::PipelineCache>,
+) -> wgpu::RenderPipeline {
+    let create_pipeline = match key.pass {
+        ScenePass::Shaded => create_shaded_pipeline,
+        ScenePass::Wireframe => create_wireframe_pipeline,
+        ScenePass::Normals => create_normals_pipeline,
+    };
+    create_pipeline(device, shader_module, bind_group_layout, key, cache)
+}
+
+fn create_shaded_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    key: &PipelineKey,
+    cache: Option<&wgpu::PipelineCache>,
+) -> wgpu::RenderPipeline {
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("render pipeline descriptor"),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader_module,
+            entry_point: Some("vs_main"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            buffers: &[VertexData::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader_module,
+            entry_point: Some("fs_main"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: key.target_format,
+                blend: key.blend,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: key.cull_mode,
+            unclipped_depth: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: key.depth_write,
+            depth_compare: wgpu::CompareFunction::Less,
+            // Every fragment writes the stencil reference, which is only set for the selected
+            // model so that its whole silhouette ends up in the stencil buffer
+            stencil: wgpu::StencilState {
+                front: SILHOUETTE_STENCIL_FACE,
+                back: SILHOUETTE_STENCIL_FACE,
+                read_mask: 0xff,
+                write_mask: 0xff,
+            },
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: key.sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache,
+    })
+}
+
+/// Draws the edges of the triangles, either rasterizing them as lines when `POLYGON_MODE_LINE`
+/// is available, or from the edge indices of the meshes otherwise.
+fn create_wireframe_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    key: &PipelineKey,
+    cache: Option<&wgpu::PipelineCache>,
+) -> wgpu::RenderPipeline {
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor (wireframe)"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let (topology, polygon_mode) = if device
+        .features()
+        .contains(wgpu::Features::POLYGON_MODE_LINE)
+    {
+        (
+            wgpu::PrimitiveTopology::TriangleList,
+            wgpu::PolygonMode::Line,
+        )
+    } else {
+        (wgpu::PrimitiveTopology::LineList, wgpu::PolygonMode::Fill)
+    };
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("render pipeline descriptor (wireframe)"),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader_module,
+            entry_point: Some("vs_wireframe"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            buffers: &[VertexData::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader_module,
+            entry_point: Some("fs_wireframe"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: key.target_format,
+                blend: key.blend,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: key.cull_mode,
+            unclipped_depth: false,
+            polygon_mode,
+            conservative: false,
+        },
+        // Depth bias doesn't apply to lines, vs_wireframe pulls them towards the camera instead
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: key.depth_write,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: key.sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache,
+    })
+}
+
+/// Draws a line along the normal of every vertex. Each vertex of a mesh is an instance of a
+/// two-vertex line, so the meshes' own vertices are drawn without any extra geometry, the object
+/// being read from the first objects buffer instead.
+fn create_normals_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    key: &PipelineKey,
+    cache: Option<&wgpu::PipelineCache>,
+) -> wgpu::RenderPipeline {
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("pipeline layout descriptor (normals)"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("render pipeline descriptor (normals)"),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader_module,
+            entry_point: Some("vs_normal"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            buffers: &[VertexData::instance_layout(), first_object_layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader_module,
+            entry_point: Some("fs_line"),
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: key.target_format,
+                blend: key.blend,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::LineList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: key.cull_mode,
+            unclipped_depth: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: key.depth_write,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: key.sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+        cache,
+    })
+}
@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashSet};
+
+/// Names defined for a shader permutation, with the text replacing them in the source, empty for
+/// names only tested by `#ifdef`.
+pub type Defines = BTreeMap<String, String>;
+
+/// Expands the directives of a WGSL file, each on a line of its own:
+///
+/// - `#include "file.wgsl"` inserts another file, only the first time it is included,
+/// - `#define NAME [text]` adds to the defines for the rest of the file,
+/// - `#ifdef NAME`, `#ifndef NAME`, `#else` and `#endif` keep lines depending on the defines.
+///
+/// Identifiers of defines with a text are replaced by it everywhere else. Directives and skipped
+/// lines are left empty, and included files are appended after the file under a comment naming
+/// them, WGSL declarations being usable before they appear. Errors in the file itself thus point
+/// to its lines, and those in included files past its end.
+pub fn preprocess(
+    file_name: &str,
+    defines: &Defines,
+    read: impl Fn(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut preprocessor = Preprocessor {
+        defines: defines.clone(),
+        included: HashSet::from([file_name.to_string()]),
+        included_output: String::new(),
+        read: &read,
+    };
+    let mut output = String::new();
+    preprocessor.expand(file_name, &read(file_name)?, &mut output)?;
+    output.push_str(&preprocessor.included_output);
+    Ok(output)
+}
+
+struct Preprocessor<'a> {
+    defines: Defines,
+    included: HashSet<String>,
+    /// Expanded included files, in the order they were done expanding.
+    included_output: String,
+    read: &'a dyn Fn(&str) -> Result<String, String>,
+}
+impl Preprocessor<'_> {
+    fn expand(&mut self, file_name: &str, source: &str, output: &mut String) -> Result<(), String> {
+        // Whether the enclosing conditional blocks keep their lines, innermost last, and whether
+        // each block is past its `#else`
+        let mut conditions: Vec<(bool, bool)> = vec![];
+        for (line_idx, line) in source.lines().enumerate() {
+            let error = |message: String| format!("{file_name}:{}: {message}", line_idx + 1);
+            let active = conditions.iter().all(|&(keep, _)| keep);
+            let Some(directive) = line.trim_start().strip_prefix('#') else {
+                if active {
+                    output.push_str(&self.substitute(line));
+                }
+                output.push('\n');
+                continue;
+            };
+            let (name, argument) = directive
+                .split_once(char::is_whitespace)
+                .map_or((directive, ""), |(name, argument)| (name, argument.trim()));
+            match name {
+                "ifdef" | "ifndef" => {
+                    let defined = self
+                        .defines
+                        .contains_key(identifier(argument).map_err(error)?);
+                    conditions.push((defined == (name == "ifdef"), false));
+                }
+                "else" => match conditions.last_mut() {
+                    Some((keep, in_else @ false)) => {
+                        *keep = !*keep;
+                        *in_else = true;
+                    }
+                    _ => return Err(error("#else without #ifdef".into())),
+                },
+                "endif" => {
+                    conditions
+                        .pop()
+                        .ok_or_else(|| error("#endif without #ifdef".into()))?;
+                }
+                _ if !active => {}
+                "define" => {
+                    let (define, text) = argument
+                        .split_once(char::is_whitespace)
+                        .map_or((argument, ""), |(define, text)| (define, text.trim()));
+                    let text = self.substitute(text);
+                    self.defines
+                        .insert(identifier(define).map_err(error)?.to_string(), text);
+                }
+                "include" => {
+                    let included = argument
+                        .strip_prefix('"')
+                        .and_then(|argument| argument.strip_suffix('"'))
+                        .ok_or_else(|| error(format!("expected a quoted file name: {argument}")))?;
+                    if self.included.insert(included.to_string()) {
+                        let source = (self.read)(included).map_err(error)?;
+                        let mut expanded = format!("// {included}\n");
+                        self.expand(included, &source, &mut expanded)?;
+                        self.included_output.push_str(&expanded);
+                    }
+                }
+                _ => return Err(error(format!("unknown directive #{name}"))),
+            }
+            output.push('\n');
+        }
+        if !conditions.is_empty() {
+            return Err(format!("{file_name}: #ifdef without #endif"));
+        }
+        Ok(())
+    }
+    /// The line with the identifiers that are defines replaced by their text.
+    fn substitute(&self, line: &str) -> String {
+        let mut output = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(start) = rest.find(is_identifier_start) {
+            output.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !is_identifier_char(c))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            // Not within a number such as 1e5 or 0x1f
+            let in_number = output.ends_with(|c: char| c.is_ascii_digit());
+            match self.defines.get(word) {
+                Some(text) if !text.is_empty() && !in_number => output.push_str(text),
+                _ => output.push_str(word),
+            }
+            rest = &rest[end..];
+        }
+        output.push_str(rest);
+        output
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn identifier(argument: &str) -> Result<&str, String> {
+    if argument.starts_with(is_identifier_start) && argument.chars().all(is_identifier_char) {
+        Ok(argument)
+    } else {
+        Err(format!("expected a name: {argument}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Preprocesses `main.wgsl` among the given files.
+    fn preprocess_files(files: &[(&str, &str)], defines: &Defines) -> Result<String, String> {
+        preprocess("main.wgsl", defines, |file_name| {
+            files
+                .iter()
+                .find(|(name, _)| *name == file_name)
+                .map(|(_, source)| source.to_string())
+                .ok_or_else(|| format!("no file {file_name}"))
+        })
+    }
+
+    #[test]
+    fn includes_files_once_after_the_including_file() {
+        let output = preprocess_files(
+            &[
+                (
+                    "main.wgsl",
+                    "#include \"a.wgsl\"\nmain\n#include \"b.wgsl\"\n#include \"a.wgsl\"\nend",
+                ),
+                ("a.wgsl", "a"),
+                ("b.wgsl", "#include \"a.wgsl\"\nb"),
+            ],
+            &Defines::new(),
+        )
+        .unwrap();
+        // The lines of the main file stay where they are
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "",
+                "main",
+                "",
+                "",
+                "end",
+                "// a.wgsl",
+                "a",
+                "// b.wgsl",
+                "",
+                "b"
+            ]
+        );
+    }
+
+    #[test]
+    fn include_cycles_end() {
+        let output = preprocess_files(
+            &[
+                ("main.wgsl", "#include \"a.wgsl\"\nmain"),
+                ("a.wgsl", "#include \"main.wgsl\"\na"),
+            ],
+            &Defines::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            ["", "main", "// a.wgsl", "", "a"]
+        );
+    }
+
+    #[test]
+    fn keeps_lines_by_nested_conditions() {
+        let source = "\
+#ifdef A
+a
+#ifndef B
+not b
+#else
+b
+#endif
+#else
+not a
+#endif";
+        let lines = |defines: &[&str]| {
+            let defines = defines
+                .iter()
+                .map(|define| (define.to_string(), String::new()))
+                .collect();
+            preprocess_files(&[("main.wgsl", source)], &defines)
+                .unwrap()
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lines(&[]), ["not a"]);
+        assert_eq!(lines(&["A"]), ["a", "not b"]);
+        assert_eq!(lines(&["A", "B"]), ["a", "b"]);
+        assert_eq!(lines(&["B"]), ["not a"]);
+    }
+
+    #[test]
+    fn substitutes_defines_outside_numbers() {
+        let defines = Defines::from([
+            ("N".to_string(), "8u".to_string()),
+            ("e5".to_string(), "oops".to_string()),
+        ]);
+        let output = preprocess_files(
+            &[(
+                "main.wgsl",
+                "#define HALF N / 2u\nlet a = N + HALF + 1e5 + NN;",
+            )],
+            &defines,
+        )
+        .unwrap();
+        assert_eq!(output, "\nlet a = 8u + 8u / 2u + 1e5 + NN;\n");
+    }
+
+    #[test]
+    fn errors_point_to_the_file_and_line() {
+        let error = |source: &str| {
+            preprocess_files(
+                &[("main.wgsl", source), ("a.wgsl", "\n#endif")],
+                &Defines::new(),
+            )
+            .unwrap_err()
+        };
+        assert_eq!(error("\n#endif"), "main.wgsl:2: #endif without #ifdef");
+        assert_eq!(error("#else"), "main.wgsl:1: #else without #ifdef");
+        assert_eq!(
+            error("#ifdef A\n#else\n#else\n#endif"),
+            "main.wgsl:3: #else without #ifdef"
+        );
+        assert_eq!(error("#ifdef A"), "main.wgsl: #ifdef without #endif");
+        assert_eq!(
+            error("#include a.wgsl"),
+            "main.wgsl:1: expected a quoted file name: a.wgsl"
+        );
+        assert_eq!(
+            error("#include \"a.wgsl\""),
+            "a.wgsl:2: #endif without #ifdef"
+        );
+        assert_eq!(error("#include \"b.wgsl\""), "main.wgsl:1: no file b.wgsl");
+        assert_eq!(
+            error("#pragma once"),
+            "main.wgsl:1: unknown directive #pragma"
+        );
+        assert_eq!(
+            error("#ifdef 1A\n#endif"),
+            "main.wgsl:1: expected a name: 1A"
+        );
+    }
+}
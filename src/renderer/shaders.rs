@@ -0,0 +1,136 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eframe::wgpu;
+
+use super::preprocessor::{Defines, preprocess};
+use crate::hot_reload::FilePoller;
+
+/// WGSL files the pipelines are created from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShaderFile {
+    Scene,
+    Culling,
+    /// Declarations included by the others.
+    Common,
+}
+impl ShaderFile {
+    pub const ALL: [Self; 3] = [Self::Scene, Self::Culling, Self::Common];
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Self::Scene => "shader.wgsl",
+            Self::Culling => "culling.wgsl",
+            Self::Common => "common.wgsl",
+        }
+    }
+    fn from_file_name(file_name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|file| file.file_name() == file_name)
+    }
+    /// Where the file is in the source tree, to edit it while the application runs.
+    pub fn path(self) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src")
+            .join(self.file_name())
+    }
+    /// The source the binary was built with.
+    fn embedded_source(self) -> &'static str {
+        match self {
+            Self::Scene => include_str!("../shader.wgsl"),
+            Self::Culling => include_str!("../culling.wgsl"),
+            Self::Common => include_str!("../common.wgsl"),
+        }
+    }
+}
+
+/// Sources of all the shader files, the ones embedded in the binary or as read from the source
+/// tree.
+#[derive(Clone)]
+pub struct ShaderSources(HashMap<ShaderFile, Cow<'static, str>>);
+impl ShaderSources {
+    pub fn embedded() -> Self {
+        Self(
+            ShaderFile::ALL
+                .into_iter()
+                .map(|file| (file, file.embedded_source().into()))
+                .collect(),
+        )
+    }
+    pub fn read() -> Result<Self, String> {
+        ShaderFile::ALL
+            .into_iter()
+            .map(|file| {
+                let path = file.path();
+                fs::read_to_string(&path)
+                    .map(|source| (file, source.into()))
+                    .map_err(|read_error| format!("{}: {read_error}", path.display()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+    /// Preprocesses a file with the given defines and compiles it. Validation errors are reported
+    /// to the device like for any other module.
+    pub fn create_module(
+        &self,
+        device: &wgpu::Device,
+        file: ShaderFile,
+        defines: &Defines,
+    ) -> Result<wgpu::ShaderModule, String> {
+        let source = preprocess(file.file_name(), defines, |file_name| {
+            ShaderFile::from_file_name(file_name)
+                .map(|file| self.0[&file].to_string())
+                .ok_or_else(|| format!("no shader file {file_name}"))
+        })?;
+        Ok(device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(file.file_name()),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        }))
+    }
+}
+
+/// Watches the shader files in the source tree while hot reloading is on, so shaders can be
+/// iterated on without rebuilding the binary. The shaders embedded in the binary are used until
+/// then.
+pub struct ShaderWatcher {
+    pub hot_reload: bool,
+    poller: FilePoller,
+    /// Whether the shaders were loaded from the source tree since hot reloading was turned on.
+    loaded: bool,
+    /// Of the last reload, until a reload succeeds.
+    pub last_error: Option<String>,
+}
+impl Default for ShaderWatcher {
+    fn default() -> Self {
+        Self {
+            // Only debug builds are likely to run next to their sources
+            hot_reload: cfg!(debug_assertions),
+            poller: FilePoller::default(),
+            loaded: false,
+            last_error: None,
+        }
+    }
+}
+impl ShaderWatcher {
+    /// Whether the shaders should be loaded from the source tree, because a file changed since
+    /// the last poll or hot reloading was just turned on.
+    pub fn poll(&mut self) -> bool {
+        if !self.hot_reload {
+            self.loaded = false;
+            return false;
+        }
+        if !self.poller.is_due() {
+            return false;
+        }
+        let mut changed = !self.loaded;
+        for file in ShaderFile::ALL {
+            changed |= self.poller.changed(file.path());
+        }
+        self.loaded = true;
+        changed
+    }
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+
+use image::{ImageReader, Rgba, RgbaImage, imageops};
+use indexmap::IndexMap;
+use tobj::Material;
+
+use crate::{
+    bounds::{Aabb, BoundingSphere},
+    models::{Camera, MaterialExt, Model},
+    scene_file,
+};
+
+/// An object of the scene picked in the outliner or the viewport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    Model(usize),
+    Mesh(usize, usize),
+    Material(usize, usize),
+}
+impl Selection {
+    pub fn model_idx(&self) -> usize {
+        match *self {
+            Selection::Model(model_idx)
+            | Selection::Mesh(model_idx, _)
+            | Selection::Material(model_idx, _) => model_idx,
+        }
+    }
+}
+
+pub struct Scene {
+    pub models: Vec<Model>,
+    pub textures_map: IndexMap<String, RgbaImage>,
+    pub camera: Camera,
+    pub selection: Option<Selection>,
+}
+impl Scene {
+    /// The scene bundled with the application, which is expected to load.
+    pub fn new(viewport_dimensions: [u32; 2]) -> Self {
+        Self::from_file("./models/default.scene", viewport_dimensions).unwrap()
+    }
+    /// Scene with the models of a scene description file.
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        viewport_dimensions: [u32; 2],
+    ) -> Result<Self, String> {
+        let camera = Camera::new(viewport_dimensions[0] as f32 / viewport_dimensions[1] as f32);
+        let mut scene = Self {
+            models: vec![],
+            camera,
+            textures_map: IndexMap::new(),
+            selection: None,
+        };
+        scene.add_scene_file(path)?;
+        Ok(scene)
+    }
+    /// Adds the models of a scene description file. Those before a model whose textures fail to
+    /// load stay added.
+    pub fn add_scene_file(&mut self, path: impl AsRef<Path>) -> Result<(), String> {
+        for model in scene_file::load(path)? {
+            self.add_model(model)?;
+        }
+        Ok(())
+    }
+    /// Adds a model, loading the textures of its materials that aren't loaded yet. Nothing is
+    /// added when one of them fails to load.
+    pub fn add_model(&mut self, model: Model) -> Result<(), String> {
+        let textures = model
+            .materials
+            .iter()
+            .filter(|material| {
+                material.has_texture() && !self.textures_map.contains_key(&material.name)
+            })
+            .map(|material| {
+                load_material_texture(material).map(|texture| (material.name.clone(), texture))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.textures_map.extend(textures);
+        self.models.push(model);
+        Ok(())
+    }
+    /// Files a model was loaded from, its textures included.
+    pub fn model_files(&self, model_idx: usize) -> Vec<PathBuf> {
+        let model = &self.models[model_idx];
+        let textures = model
+            .materials
+            .iter()
+            .flat_map(|material| material.texture_names())
+            .map(texture_path);
+        model
+            .obj_path
+            .iter()
+            .chain(&model.mtl_paths)
+            .cloned()
+            .chain(textures)
+            .collect()
+    }
+    /// Loads a model and its textures again from its files, keeping its transform, instances and
+    /// the selection. The model doesn't change if any file fails to load.
+    pub fn reload_model(&mut self, model_idx: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let model = &self.models[model_idx];
+        let Some(obj_path) = &model.obj_path else {
+            return Ok(());
+        };
+        let mut reloaded =
+            Model::load(obj_path, (model.translation, model.rotation, model.scaling))?;
+        let textures = reloaded
+            .materials
+            .iter()
+            .filter(|material| material.has_texture())
+            .map(|material| {
+                load_material_texture(material).map(|texture| (material.name.clone(), texture))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        reloaded.name = model.name.clone();
+        reloaded.visible = model.visible;
+        reloaded.instances = model.instances.clone();
+        self.models[model_idx] = reloaded;
+        self.textures_map.extend(textures);
+        self.retain_used_textures();
+
+        let model = &self.models[model_idx];
+        self.selection = match self.selection {
+            Some(Selection::Mesh(idx, mesh_idx))
+                if idx == model_idx && mesh_idx >= model.meshes.len() =>
+            {
+                Some(Selection::Model(model_idx))
+            }
+            Some(Selection::Material(idx, material_idx))
+                if idx == model_idx && material_idx >= model.materials.len() =>
+            {
+                Some(Selection::Model(model_idx))
+            }
+            selection => selection,
+        };
+        Ok(())
+    }
+    /// World bounds of the visible models.
+    pub fn bounds(&self) -> Aabb {
+        self.models
+            .iter()
+            .filter(|model| model.visible)
+            .fold(Aabb::EMPTY, |aabb, model| {
+                aabb.merge(&model.world_bounds().aabb)
+            })
+    }
+    /// Points the camera at the selected model, or at the whole scene without a selection.
+    pub fn frame_selection(&mut self) {
+        let sphere = match self.selection {
+            Some(selection) => self.models[selection.model_idx()].world_bounds().sphere,
+            None => {
+                let aabb = self.bounds();
+                if aabb.is_empty() {
+                    return;
+                }
+                BoundingSphere::from_aabb(&aabb)
+            }
+        };
+        self.camera.frame(&sphere);
+    }
+    /// Removes a model along with the textures no other model uses.
+    pub fn remove_model(&mut self, model_idx: usize) {
+        self.models.remove(model_idx);
+        self.retain_used_textures();
+        self.selection = None;
+    }
+    fn retain_used_textures(&mut self) {
+        let models = &self.models;
+        self.textures_map.retain(|material_name, _| {
+            models
+                .iter()
+                .flat_map(|model| &model.materials)
+                .any(|material| &material.name == material_name && material.has_texture())
+        });
+    }
+}
+
+/// Textures are looked up in the models directory, whichever directory the model is in.
+fn texture_path(dt_name: &str) -> PathBuf {
+    Path::new("./models").join(dt_name)
+}
+
+/// The diffuse texture of a material, or plain white without one, with its dissolve texture
+/// multiplying the alpha channel, so that both are sampled at once.
+fn load_material_texture(material: &Material) -> Result<RgbaImage, String> {
+    let dissolve = material
+        .dissolve_texture
+        .as_deref()
+        .map(load_texture)
+        .transpose()?;
+    let mut texture = match (&material.diffuse_texture, &dissolve) {
+        (Some(dt_name), _) => load_texture(dt_name)?,
+        (None, Some(dissolve)) => {
+            RgbaImage::from_pixel(dissolve.width(), dissolve.height(), Rgba([255; 4]))
+        }
+        (None, None) => RgbaImage::from_pixel(1, 1, Rgba([255; 4])),
+    };
+    if let Some(mut dissolve) = dissolve {
+        if dissolve.dimensions() != texture.dimensions() {
+            dissolve = imageops::resize(
+                &dissolve,
+                texture.width(),
+                texture.height(),
+                imageops::FilterType::Triangle,
+            );
+        }
+        // Masks are either grayscale or in the alpha channel of an otherwise white image
+        for (pixel, mask) in texture.pixels_mut().zip(dissolve.pixels()) {
+            let [r, g, b, a] = mask.0.map(u32::from);
+            let luma = (r * 299 + g * 587 + b * 114) / 1000;
+            pixel.0[3] = (u32::from(pixel.0[3]) * luma * a / (255 * 255)) as u8;
+        }
+    }
+    Ok(texture)
+}
+
+fn load_texture(dt_name: &str) -> Result<RgbaImage, String> {
+    let dt_path = texture_path(dt_name);
+    let dt_data = ImageReader::open(&dt_path)
+        .map_err(image::ImageError::IoError)
+        .and_then(|reader| reader.decode())
+        .map_err(|error| format!("{}: {error}", dt_path.display()))?;
+    Ok(dt_data.to_rgba8())
+}
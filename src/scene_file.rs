@@ -0,0 +1,187 @@
+use std::{fs, path::Path};
+
+use nalgebra::Vector3;
+
+use crate::{models::Model, primitives::Primitive};
+
+/// A model of a scene description file, before it is loaded or generated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneEntry {
+    /// OBJ file, relative to the scene description file.
+    Model(String, Transform),
+    Primitive(Primitive, Transform),
+}
+
+/// Translation, rotation as a scaled axis in radians, and scaling, like the fields of `Model`.
+pub type Transform = (Vector3<f32>, Vector3<f32>, Vector3<f32>);
+
+/// Loads the models of a scene description file. Errors name the file that failed.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Model>, String> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path)
+        .map_err(|read_error| format!("{}: {read_error}", path.display()))?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    parse(&path.display().to_string(), &source)?
+        .into_iter()
+        .map(|entry| match entry {
+            SceneEntry::Model(obj_path, transform) => {
+                Model::load(directory.join(obj_path), transform)
+            }
+            SceneEntry::Primitive(primitive, transform) => {
+                Ok(Model::primitive(primitive, transform))
+            }
+        })
+        .collect()
+}
+
+/// Parses a scene description, one model per line:
+///
+/// - `model file.obj` loads an OBJ file, relative to the scene description file,
+/// - `primitive name` generates a primitive by its `Primitive::identifier`, followed by its
+///   parameters as `name=value`, those not given keeping their default,
+///
+/// each followed by any of `position=x,y,z`, `rotation=x,y,z` and `scaling=x,y,z` or
+/// `scaling=s`. Empty lines and those starting with `#` are skipped.
+pub fn parse(file_name: &str, source: &str) -> Result<Vec<SceneEntry>, String> {
+    let mut entries = vec![];
+    for (line_idx, line) in source.lines().enumerate() {
+        let error = |message: String| format!("{file_name}:{}: {message}", line_idx + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let (Some(kind), Some(name)) = (words.next(), words.next()) else {
+            return Err(error(format!("expected a kind and a name: {line}")));
+        };
+        let mut primitive = match kind {
+            "model" => None,
+            "primitive" => Some(
+                Primitive::ALL
+                    .into_iter()
+                    .find(|primitive| primitive.identifier() == name)
+                    .ok_or_else(|| error(format!("unknown primitive {name}")))?,
+            ),
+            _ => return Err(error(format!("unknown kind {kind}"))),
+        };
+        let mut transform = (Vector3::zeros(), Vector3::zeros(), Vector3::repeat(1.0));
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected name=value: {word}")))?;
+            match (key, &mut primitive) {
+                ("position", _) => transform.0 = parse_vector(value).map_err(error)?,
+                ("rotation", _) => transform.1 = parse_vector(value).map_err(error)?,
+                ("scaling", _) => {
+                    transform.2 = match value.parse() {
+                        Ok(scaling) => Vector3::repeat(scaling),
+                        Err(_) => parse_vector(value).map_err(error)?,
+                    }
+                }
+                (_, Some(primitive)) => primitive.set_parameter(key, value).map_err(error)?,
+                (_, None) => return Err(error(format!("unknown parameter {key}"))),
+            }
+        }
+        entries.push(match primitive {
+            Some(primitive) => SceneEntry::Primitive(primitive, transform),
+            None => SceneEntry::Model(name.to_string(), transform),
+        });
+    }
+    Ok(entries)
+}
+
+fn parse_vector(value: &str) -> Result<Vector3<f32>, String> {
+    let components = value
+        .split(',')
+        .map(|component| component.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|parse_error| format!("{value}: {parse_error}"))?;
+    match components[..] {
+        [x, y, z] => Ok(Vector3::new(x, y, z)),
+        _ => Err(format!("expected x,y,z: {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_models_and_primitives() {
+        let source = "\
+# Comment
+
+model teapot.obj position=1,2,3 scaling=0.01
+primitive torus sides=12 minor_radius=0.25 rotation=0,1.5,0 scaling=1,2,3
+primitive box
+";
+        let entries = parse("test.scene", source).unwrap();
+        assert_eq!(
+            entries,
+            [
+                SceneEntry::Model(
+                    "teapot.obj".to_string(),
+                    (
+                        Vector3::new(1.0, 2.0, 3.0),
+                        Vector3::zeros(),
+                        Vector3::repeat(0.01)
+                    )
+                ),
+                SceneEntry::Primitive(
+                    Primitive::Torus {
+                        segments: 48,
+                        sides: 12,
+                        minor_radius: 0.25
+                    },
+                    (
+                        Vector3::zeros(),
+                        Vector3::new(0.0, 1.5, 0.0),
+                        Vector3::new(1.0, 2.0, 3.0)
+                    )
+                ),
+                SceneEntry::Primitive(
+                    Primitive::Box { subdivisions: 1 },
+                    (Vector3::zeros(), Vector3::zeros(), Vector3::repeat(1.0))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn loads_the_default_scene() {
+        let models = load("./models/default.scene").unwrap();
+        assert_eq!(
+            models
+                .iter()
+                .map(|model| model.name.as_str())
+                .collect::<Vec<_>>(),
+            ["teapot", "cube"]
+        );
+    }
+
+    #[test]
+    fn errors_point_to_the_line() {
+        let error = |source| parse("test.scene", source).unwrap_err();
+        assert_eq!(
+            error("\nprimitive pyramid"),
+            "test.scene:2: unknown primitive pyramid"
+        );
+        assert_eq!(
+            error("primitive box segments=3"),
+            "test.scene:1: Box has no parameter segments"
+        );
+        assert_eq!(
+            error("model a.obj subdivisions=3"),
+            "test.scene:1: unknown parameter subdivisions"
+        );
+        assert_eq!(
+            error("model a.obj position=1,2"),
+            "test.scene:1: expected x,y,z: 1,2"
+        );
+        assert_eq!(error("light sun"), "test.scene:1: unknown kind light");
+        assert_eq!(
+            error("model"),
+            "test.scene:1: expected a kind and a name: model"
+        );
+    }
+}
@@ -2,36 +2,282 @@ struct VertexInput{
   @location(0) position: vec3<f32>,
   @location(1) normal: vec3<f32>,
   @location(2) uv: vec2<f32>,
-  @location(3) modelIdx: u32,
 }
 struct VertexOutput {
     @location(0) uv: vec2<f32>,
-    @location(1) modelIdx: u32,
+    @location(1) objectIdx: u32,
+    @location(2) normal: vec3<f32>, // In world space, not normalized
+    @location(3) viewDepth: f32,    // Distance along the view direction
     @builtin(position) position: vec4<f32>,
 };
 
-struct ObjectData {
-  modelMatrix: mat4x4<f32>, // Position, rotation, scale
-  //textureIndex: u32,        // Which texture in the array to use
-};
-@group(0) @binding(0) var<storage, read> objects: array<ObjectData>;
+#include "common.wgsl"
+
 @group(0) @binding(1) var myTextures: binding_array<texture_2d<f32>>;
 @group(0) @binding(2) var mySampler: sampler;
-@group(0) @binding(3) var<uniform> projection: mat4x4<f32>;
 
+struct FrameData {
+  projection: mat4x4<f32>,        // World to clip space
+  inverseProjection: mat4x4<f32>, // Clip to world space
+  view: mat4x4<f32>,              // World to view space
+  normalLength: f32,              // Of the normals overlay, in world units
+  aspectRatio: f32,               // Of the viewport, width over height
+};
+@group(0) @binding(3) var<uniform> frame: FrameData;
+
+// Must match the renderer's DebugView. DEBUG_VIEW, one of them, is defined by the renderer for
+// every shader permutation
+const DEBUG_VIEW_WIREFRAME: u32 = 1u;
+const DEBUG_VIEW_NORMALS: u32 = 3u;
+const DEBUG_VIEW_UV_CHECKER: u32 = 4u;
+const DEBUG_VIEW_DEPTH: u32 = 5u;
+const DEBUG_VIEW_MESH_COLORS: u32 = 6u;
+const DEBUG_VIEW_BACKFACES: u32 = 7u;
+
+// Draws start their instances at the object of the first one, so that the instance index is
+// the object index, the other instances' objects following the first one's
 @vertex
-fn vs_main( input: VertexInput ) -> VertexOutput {
-    let obj = objects[input.modelIdx];
+fn vs_main(input: VertexInput, @builtin(instance_index) instanceIdx: u32) -> VertexOutput {
+    return transformVertex(input, instanceIdx);
+}
+
+fn transformVertex(input: VertexInput, objectIdx: u32) -> VertexOutput {
+    let obj = objects[objectIdx];
 
     var output: VertexOutput;
-    output.position = projection * obj.modelMatrix * vec4<f32>(input.position, 1.0);
+    output.position = frame.projection * obj.modelMatrix * vec4<f32>(input.position, 1.0);
     output.uv = input.uv;
-    output.modelIdx = input.modelIdx;
+    output.objectIdx = objectIdx;
+    output.normal = obj.normalMatrix * input.normal;
+    output.viewDepth = output.position.w;
     return output;
 }
 
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(myTextures[in.modelIdx], mySampler, in.uv);
+fn fs_main(in: VertexOutput, @builtin(front_facing) frontFacing: bool) -> @location(0) vec4<f32> {
+    let obj = objects[in.objectIdx];
+#ifdef TEXTURED
+    let color = textureSample(myTextures[obj.textureIndex], mySampler, in.uv) * obj.diffuse;
+#else
+    let color = obj.diffuse;
+#endif
+#ifdef ALPHA_CUTOUT
+    // The dissolve texture is in the alpha channel, either opaque or fully transparent. What is
+    // kept is opaque, as egui blends the viewport over the panels with its alpha
+    if color.a < 0.5 {
+        discard;
+    }
+    let shaded = vec4<f32>(color.rgb, 1.0);
+#else
+    let shaded = color;
+#endif
+    switch DEBUG_VIEW {
+        case DEBUG_VIEW_NORMALS: {
+            // Missing normals stay black
+            if length(in.normal) < 1e-6 {
+                return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+            }
+            return vec4<f32>(normalize(in.normal) * 0.5 + 0.5, 1.0);
+        }
+        case DEBUG_VIEW_UV_CHECKER: {
+            let cell = vec2<i32>(floor(in.uv * 8.0));
+            let checker = f32((cell.x + cell.y) & 1);
+            return vec4<f32>(mix(vec3<f32>(0.2), vec3<f32>(0.9), checker) * vec3<f32>(fract(in.uv), 1.0), 1.0);
+        }
+        case DEBUG_VIEW_DEPTH: {
+            return vec4<f32>(vec3<f32>(1.0 - exp(-0.3 * in.viewDepth)), 1.0);
+        }
+        case DEBUG_VIEW_MESH_COLORS: {
+            return vec4<f32>(meshColor(in.objectIdx), 1.0);
+        }
+        case DEBUG_VIEW_BACKFACES: {
+            return select(vec4<f32>(1.0, 0.0, 0.0, 1.0), shaded, frontFacing);
+        }
+        default: {
+            return shaded;
+        }
+    }
     //return vec4<f32>(0.3, 0.2, 0.1, 1.0);
 }
+
+// Arbitrary but stable color of an object
+fn meshColor(objectIdx: u32) -> vec3<f32> {
+    var hash = objectIdx * 747796405u + 2891336453u;
+    hash = ((hash >> ((hash >> 28u) + 4u)) ^ hash) * 277803737u;
+    hash = (hash >> 22u) ^ hash;
+    return vec3<f32>(f32(hash & 0xffu), f32((hash >> 8u) & 0xffu), f32((hash >> 16u) & 0xffu)) / 255.0;
+}
+
+@vertex
+fn vs_wireframe(input: VertexInput, @builtin(instance_index) instanceIdx: u32) -> VertexOutput {
+    var output = transformVertex(input, instanceIdx);
+    // Pull the lines slightly towards the camera so that they win the depth test against the
+    // triangles they lie on
+    output.position.z -= 0.0005 * output.position.w;
+    return output;
+}
+
+@fragment
+fn fs_wireframe(in: VertexOutput) -> @location(0) vec4<f32> {
+    if DEBUG_VIEW == DEBUG_VIEW_WIREFRAME {
+        return vec4<f32>(meshColor(in.objectIdx), 1.0);
+    }
+    return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+}
+
+struct OutlineData {
+  color: vec4<f32>,
+  viewportSize: vec2<f32>,
+  width: f32, // In pixels
+};
+@group(1) @binding(0) var<uniform> outline: OutlineData;
+
+@vertex
+fn vs_outline(input: VertexInput, @builtin(instance_index) instanceIdx: u32) -> @builtin(position) vec4<f32> {
+    // Each instance of the model is drawn OUTLINE_DIRECTIONS times, shifted in a different
+    // direction around the silhouette, the draw starting at OUTLINE_DIRECTIONS times the object of
+    // the first instance
+    let obj = objects[instanceIdx / OUTLINE_DIRECTIONS];
+    let position = frame.projection * obj.modelMatrix * vec4<f32>(input.position, 1.0);
+    let angle = f32(instanceIdx % OUTLINE_DIRECTIONS) * 6.2831853 / f32(OUTLINE_DIRECTIONS);
+    let offset = vec2<f32>(cos(angle), sin(angle)) * outline.width * 2.0 / outline.viewportSize;
+    return vec4<f32>(position.xy + offset * position.w, position.zw);
+}
+
+@fragment
+fn fs_outline() -> @location(0) vec4<f32> {
+    return outline.color;
+}
+
+struct LineInput {
+  @location(0) position: vec3<f32>,
+  @location(1) color: vec4<f32>,
+}
+struct LineOutput {
+    @location(0) color: vec4<f32>,
+    @builtin(position) position: vec4<f32>,
+};
+
+@vertex
+fn vs_line(input: LineInput) -> LineOutput {
+    var output: LineOutput;
+    output.position = frame.projection * vec4<f32>(input.position, 1.0);
+    output.color = input.color;
+    return output;
+}
+
+@fragment
+fn fs_line(in: LineOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
+
+// Vertex 0 is at the mesh vertex given by the instance, vertex 1 at the tip of its normal. The
+// instances being taken by the mesh vertices, the object comes from a buffer of its own
+@vertex
+fn vs_normal(
+    input: VertexInput,
+    @location(3) objectIdx: u32,
+    @builtin(vertex_index) vertexIdx: u32,
+) -> LineOutput {
+    let obj = objects[objectIdx];
+    let normal = obj.normalMatrix * input.normal;
+    var direction = vec3<f32>(0.0, 1.0, 0.0);
+    var output: LineOutput;
+    if length(normal) < 1e-6 {
+        // Missing normals show up as red ticks
+        output.color = vec4<f32>(1.0, 0.0, 0.0, 1.0);
+    } else {
+        direction = normalize(normal);
+        output.color = vec4<f32>(direction * 0.5 + 0.5, 1.0);
+    }
+    let position = (obj.modelMatrix * vec4<f32>(input.position, 1.0)).xyz
+        + direction * frame.normalLength * f32(vertexIdx);
+    output.position = frame.projection * vec4<f32>(position, 1.0);
+    return output;
+}
+
+struct GridOutput {
+    @location(0) ndc: vec2<f32>,
+    @builtin(position) position: vec4<f32>,
+};
+struct GridFragment {
+    @location(0) color: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+};
+
+// A single triangle covering the whole viewport
+@vertex
+fn vs_grid(@builtin(vertex_index) vertexIdx: u32) -> GridOutput {
+    let ndc = vec2<f32>(f32((vertexIdx << 1u) & 2u), f32(vertexIdx & 2u)) * 2.0 - 1.0;
+    var output: GridOutput;
+    output.position = vec4<f32>(ndc, 0.0, 1.0);
+    output.ndc = ndc;
+    return output;
+}
+
+fn unproject(ndc: vec2<f32>, depth: f32) -> vec3<f32> {
+    let position = frame.inverseProjection * vec4<f32>(ndc, depth, 1.0);
+    return position.xyz / position.w;
+}
+
+// Coverage of the lines every `spacing` units, about one pixel wide whatever the distance
+fn gridLines(coordinates: vec2<f32>, spacing: f32) -> f32 {
+    let scaled = coordinates / spacing;
+    let distance = abs(fract(scaled - 0.5) - 0.5) / fwidth(scaled);
+    return 1.0 - min(min(distance.x, distance.y), 1.0);
+}
+
+@fragment
+fn fs_grid(in: GridOutput) -> GridFragment {
+    // Intersect the ray of the pixel with the y = 0 plane. nalgebra's perspective maps the near
+    // plane to a depth of -1
+    let near = unproject(in.ndc, -1.0);
+    let far = unproject(in.ndc, 1.0);
+    let t = -near.y / (far.y - near.y);
+    let position = near + t * (far - near);
+
+    // Derivatives are computed before any fragment is discarded
+    let distance = length(position - near);
+    let minor = gridLines(position.xz, 0.1) * (1.0 - smoothstep(2.0, 10.0, distance));
+    let major = gridLines(position.xz, 1.0);
+    var color = vec4<f32>(0.6, 0.6, 0.6, max(minor * 0.3, major * 0.6));
+    let axisWidth = fwidth(position.xz);
+    if abs(position.x) < axisWidth.x {
+        color = vec4<f32>(0.2, 0.4, 1.0, 1.0); // Z axis
+    }
+    if abs(position.z) < axisWidth.y {
+        color = vec4<f32>(0.9, 0.2, 0.2, 1.0); // X axis
+    }
+    color.a *= 1.0 - smoothstep(10.0, 50.0, distance);
+
+    let clipPosition = frame.projection * vec4<f32>(position, 1.0);
+    let depth = clipPosition.z / clipPosition.w;
+    // Behind the camera, or outside of the depth range
+    if t <= 0.0 || t >= 1.0 || depth < 0.0 || depth > 1.0 {
+        discard;
+    }
+    var output: GridFragment;
+    output.color = color;
+    output.depth = depth;
+    return output;
+}
+
+// Three lines starting at a corner of the viewport, along the world axes as seen by the camera
+@vertex
+fn vs_axes_indicator(@builtin(vertex_index) vertexIdx: u32) -> LineOutput {
+    var colors = array<vec4<f32>, 3>(
+        vec4<f32>(0.9, 0.2, 0.2, 1.0),
+        vec4<f32>(0.2, 0.9, 0.2, 1.0),
+        vec4<f32>(0.2, 0.4, 1.0, 1.0),
+    );
+    let axisIdx = vertexIdx / 2u;
+    var axis = vec3<f32>(0.0);
+    axis[axisIdx] = 1.0;
+    let direction = (frame.view * vec4<f32>(axis, 0.0)).xy;
+    let offset = direction * 0.12 * f32(vertexIdx % 2u);
+    var output: LineOutput;
+    // Undo the aspect ratio so that the axes keep their length on screen
+    output.position = vec4<f32>(vec2<f32>(-0.85, -0.8) + vec2<f32>(offset.x / frame.aspectRatio, offset.y), 0.0, 1.0);
+    output.color = colors[axisIdx];
+    return output;
+}
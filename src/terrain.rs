@@ -0,0 +1,336 @@
+use std::{f32::consts::PI, path::PathBuf};
+
+use image::ImageReader;
+use nalgebra::Vector3;
+use tobj::Mesh;
+
+use crate::{
+    mesh_optimizer,
+    mesh_simplifier::{Lod, MAX_LOD_LEVELS},
+    models::Model,
+};
+
+/// Where the heights of a terrain come from, as values from 0 to 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Heights {
+    /// Grayscale image seen from above, its top towards -Z, black being the lowest.
+    Image(PathBuf),
+    /// Sum of octaves of gradient noise, each twice the frequency and half the amplitude of the
+    /// previous one.
+    Noise {
+        seed: u32,
+        /// Features of the first octave across the terrain.
+        frequency: f32,
+        octaves: u32,
+    },
+}
+
+/// Ground built from a heightfield, split into square chunks that are culled and simplified on
+/// their own. It becomes a model of one mesh per chunk, centered on the origin on the XZ plane.
+#[derive(Debug, Clone)]
+pub struct Terrain {
+    pub heights: Heights,
+    /// Extent along X and Z.
+    pub size: f32,
+    /// Of the highest point above the lowest one.
+    pub height: f32,
+    /// Along each side.
+    pub chunks: u32,
+    /// Quads along each side of a chunk at full detail. Each level of detail halves them, as
+    /// long as they divide evenly.
+    pub chunk_resolution: u32,
+    /// Times the texture repeats along each side.
+    pub uv_tiling: f32,
+    /// Of the skirts hanging from the borders of the chunks, which hide the cracks between chunks
+    /// at different levels of detail. At least the error of the coarsest level of the chunk.
+    pub skirt_depth: f32,
+}
+impl Default for Terrain {
+    fn default() -> Self {
+        Self {
+            heights: Heights::Noise {
+                seed: 0,
+                frequency: 4.0,
+                octaves: 5,
+            },
+            size: 10.0,
+            height: 1.5,
+            chunks: 8,
+            chunk_resolution: 32,
+            uv_tiling: 8.0,
+            skirt_depth: 0.1,
+        }
+    }
+}
+impl Terrain {
+    /// Generates the chunks, with their levels of detail. Errors name the heightmap that failed
+    /// to load.
+    pub fn model(&self) -> Result<Model, String> {
+        let terrain = Terrain {
+            chunks: self.chunks.max(1),
+            chunk_resolution: self.chunk_resolution.max(1),
+            ..self.clone()
+        };
+        let heightfield = Heightfield::new(&terrain)?;
+        let mut meshes = vec![];
+        let mut lods = vec![];
+        for chunk_z in 0..terrain.chunks {
+            for chunk_x in 0..terrain.chunks {
+                let (mesh, chunk_lods) = terrain.chunk(&heightfield, chunk_x, chunk_z);
+                meshes.push(mesh);
+                lods.push(chunk_lods);
+            }
+        }
+        Ok(Model::with_lods(
+            "Terrain".to_string(),
+            meshes,
+            lods,
+            vec![],
+            (Vector3::zeros(), Vector3::zeros(), Vector3::repeat(1.0)),
+        ))
+    }
+    fn chunk(&self, heightfield: &Heightfield, chunk_x: u32, chunk_z: u32) -> (Mesh, Vec<Lod>) {
+        let resolution = self.chunk_resolution;
+        let first_column = chunk_x * resolution;
+        let first_row = chunk_z * resolution;
+        // Each level skips every other vertex of the previous one
+        let steps = (0..=MAX_LOD_LEVELS as u32)
+            .map(|level| 1 << level)
+            .take_while(|&step| resolution.is_multiple_of(step))
+            .collect::<Vec<u32>>();
+        let errors = steps
+            .iter()
+            .map(|&step| {
+                heightfield.simplification_error(first_column, first_row, resolution, step)
+            })
+            .collect::<Vec<_>>();
+        let skirt_depth = errors
+            .iter()
+            .fold(self.skirt_depth, |depth, &error| depth.max(error));
+
+        let mut mesh = Mesh::default();
+        let mut add_vertex = |column: u32, row: u32, depth: f32| {
+            let [u, v] = heightfield.uv(column, row);
+            let position = heightfield.position(column, row) - Vector3::y() * depth;
+            mesh.positions.extend(position.iter());
+            mesh.normals.extend(heightfield.normal(column, row).iter());
+            mesh.texcoords
+                .extend([u * self.uv_tiling, (1.0 - v) * self.uv_tiling]);
+        };
+        for row in 0..=resolution {
+            for column in 0..=resolution {
+                add_vertex(first_column + column, first_row + row, 0.0);
+            }
+        }
+        // The borders, walked with the chunk on their right seen from above for the skirts to
+        // face out
+        let borders = [
+            (0..=resolution).map(|i| (i, 0)).collect::<Vec<_>>(),
+            (0..=resolution).map(|i| (resolution, i)).collect(),
+            (0..=resolution).rev().map(|i| (i, resolution)).collect(),
+            (0..=resolution).rev().map(|i| (0, i)).collect(),
+        ];
+        let grid_vertex = |column: u32, row: u32| row * (resolution + 1) + column;
+        let first_skirt_vertex = grid_vertex(0, resolution + 1);
+        for border in &borders {
+            for &(column, row) in border {
+                add_vertex(first_column + column, first_row + row, skirt_depth);
+            }
+        }
+        let vertex_count = mesh.positions.len() / 3;
+
+        let mut levels = vec![];
+        for (&step, &error) in steps.iter().zip(&errors) {
+            let mut indices = vec![];
+            for row in (0..resolution).step_by(step as usize) {
+                for column in (0..resolution).step_by(step as usize) {
+                    let a = grid_vertex(column, row);
+                    let b = grid_vertex(column + step, row);
+                    let c = grid_vertex(column + step, row + step);
+                    let d = grid_vertex(column, row + step);
+                    indices.extend([a, d, c, a, c, b]);
+                }
+            }
+            for (border_idx, border) in borders.iter().enumerate() {
+                let first_skirt = first_skirt_vertex + border_idx as u32 * (resolution + 1);
+                for i in (0..resolution).step_by(step as usize) {
+                    let [a, b] = [border[i as usize], border[(i + step) as usize]]
+                        .map(|(column, row)| grid_vertex(column, row));
+                    let [below_a, below_b] = [first_skirt + i, first_skirt + i + step];
+                    indices.extend([a, b, below_b, a, below_b, below_a]);
+                }
+            }
+            levels.push(Lod {
+                indices: mesh_optimizer::optimize_vertex_cache(&indices, vertex_count),
+                error,
+            });
+        }
+        let full_detail = levels.remove(0);
+        mesh.indices = full_detail.indices;
+        (mesh, levels)
+    }
+}
+
+/// Heights at every vertex of the terrain, before splitting it into chunks.
+struct Heightfield {
+    /// Vertices along each side.
+    columns: u32,
+    heights: Vec<f32>,
+    size: f32,
+}
+impl Heightfield {
+    fn new(terrain: &Terrain) -> Result<Self, String> {
+        let columns = terrain.chunks * terrain.chunk_resolution + 1;
+        let sample: Box<dyn Fn(f32, f32) -> f32> = match &terrain.heights {
+            Heights::Image(path) => {
+                let image = ImageReader::open(path)
+                    .map_err(image::ImageError::IoError)
+                    .and_then(|reader| reader.decode())
+                    .map_err(|error| format!("{}: {error}", path.display()))?
+                    .to_luma32f();
+                Box::new(move |u, v| {
+                    // Bilinear interpolation between the pixel centers
+                    let x = (u * image.width() as f32 - 0.5).clamp(0.0, image.width() as f32 - 1.0);
+                    let y =
+                        (v * image.height() as f32 - 0.5).clamp(0.0, image.height() as f32 - 1.0);
+                    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+                    let (x1, y1) = (
+                        (x0 + 1).min(image.width() - 1),
+                        (y0 + 1).min(image.height() - 1),
+                    );
+                    let pixel = |x, y| image.get_pixel(x, y).0[0];
+                    let (tx, ty) = (x.fract(), y.fract());
+                    let top = pixel(x0, y0) * (1.0 - tx) + pixel(x1, y0) * tx;
+                    let bottom = pixel(x0, y1) * (1.0 - tx) + pixel(x1, y1) * tx;
+                    top * (1.0 - ty) + bottom * ty
+                })
+            }
+            &Heights::Noise {
+                seed,
+                frequency,
+                octaves,
+            } => Box::new(move |u, v| {
+                let mut value = 0.0;
+                let mut amplitude = 0.5;
+                let mut octave_frequency = frequency;
+                for octave in 0..octaves.max(1) {
+                    let octave_seed = seed.wrapping_add(octave);
+                    value += amplitude
+                        * gradient_noise(u * octave_frequency, v * octave_frequency, octave_seed);
+                    amplitude /= 2.0;
+                    octave_frequency *= 2.0;
+                }
+                // Rarely past -0.5 or 0.5
+                (value + 0.5).clamp(0.0, 1.0)
+            }),
+        };
+        let last = (columns - 1) as f32;
+        let heights = (0..columns)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| sample(column as f32 / last, row as f32 / last) * terrain.height)
+            .collect();
+        Ok(Self {
+            columns,
+            heights,
+            size: terrain.size,
+        })
+    }
+    fn height(&self, column: u32, row: u32) -> f32 {
+        self.heights[(row * self.columns + column) as usize]
+    }
+    fn spacing(&self) -> f32 {
+        self.size / (self.columns - 1) as f32
+    }
+    /// Across the terrain, from 0 to 1 towards +X and +Z.
+    fn uv(&self, column: u32, row: u32) -> [f32; 2] {
+        let last = (self.columns - 1) as f32;
+        [column as f32 / last, row as f32 / last]
+    }
+    fn position(&self, column: u32, row: u32) -> Vector3<f32> {
+        let [u, v] = self.uv(column, row);
+        Vector3::new(
+            (u - 0.5) * self.size,
+            self.height(column, row),
+            (v - 0.5) * self.size,
+        )
+    }
+    /// From the slopes to the neighbors on both sides, so that it is the same on both sides of
+    /// the borders between chunks.
+    fn normal(&self, column: u32, row: u32) -> Vector3<f32> {
+        let last = self.columns - 1;
+        let [left, right] = [column.saturating_sub(1), (column + 1).min(last)];
+        let [back, front] = [row.saturating_sub(1), (row + 1).min(last)];
+        let slope_x = (self.height(right, row) - self.height(left, row))
+            / ((right - left) as f32 * self.spacing());
+        let slope_z = (self.height(column, front) - self.height(column, back))
+            / ((front - back) as f32 * self.spacing());
+        Vector3::new(-slope_x, 1.0, -slope_z).normalize()
+    }
+    /// Largest height difference between the vertices of a chunk and its surface when only
+    /// every `step` vertex is kept, split along the same diagonals.
+    fn simplification_error(
+        &self,
+        first_column: u32,
+        first_row: u32,
+        resolution: u32,
+        step: u32,
+    ) -> f32 {
+        let mut error = 0.0f32;
+        for row in 0..=resolution {
+            for column in 0..=resolution {
+                let (quad_column, quad_row) = (
+                    (column / step * step).min(resolution - step),
+                    (row / step * step).min(resolution - step),
+                );
+                let x = (column - quad_column) as f32 / step as f32;
+                let z = (row - quad_row) as f32 / step as f32;
+                let corner = |column_offset: u32, row_offset: u32| {
+                    self.height(
+                        first_column + quad_column + column_offset * step,
+                        first_row + quad_row + row_offset * step,
+                    )
+                };
+                let [a, b, c, d] = [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)];
+                let interpolated = if z >= x {
+                    a + (c - d) * x + (d - a) * z
+                } else {
+                    a + (b - a) * x + (c - b) * z
+                };
+                let height = self.height(first_column + column, first_row + row);
+                error = error.max((height - interpolated).abs());
+            }
+        }
+        error
+    }
+}
+
+/// Perlin's gradient noise: random gradients at the integer lattice points, smoothly blended.
+/// Roughly from -0.7 to 0.7.
+fn gradient_noise(x: f32, y: f32, seed: u32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let contribution = |corner_x: f32, corner_y: f32| {
+        let hash = hash(corner_x as i32 as u32, corner_y as i32 as u32, seed);
+        let angle = hash as f32 / u32::MAX as f32 * 2.0 * PI;
+        let (sin, cos) = angle.sin_cos();
+        cos * (x - corner_x) + sin * (y - corner_y)
+    };
+    let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (fx, fy) = (fade(tx), fade(ty));
+    let top = contribution(x0, y0) * (1.0 - fx) + contribution(x0 + 1.0, y0) * fx;
+    let bottom = contribution(x0, y0 + 1.0) * (1.0 - fx) + contribution(x0 + 1.0, y0 + 1.0) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+/// Integer hash mixing the bits of a lattice point and a seed.
+fn hash(x: u32, y: u32, seed: u32) -> u32 {
+    let mut hash = x
+        .wrapping_mul(0x8da6_b343)
+        .wrapping_add(y.wrapping_mul(0xd816_3841))
+        .wrapping_add(seed.wrapping_mul(0xcb1a_b31f));
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x2c1b_3c6d);
+    hash ^= hash >> 12;
+    hash = hash.wrapping_mul(0x297a_2d39);
+    hash ^ (hash >> 15)
+}
@@ -1,19 +1,35 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
-use image::{ImageReader, RgbaImage};
-use indexmap::IndexMap;
-use log::info;
-use nalgebra::Vector3;
+use eframe::wgpu;
+use log::{error, info};
 use winit::application::ApplicationHandler;
-use winit::event::WindowEvent;
+use winit::event::{MouseButton, WindowEvent};
 use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
 use winit::keyboard::KeyCode;
 use winit::window::{Window, WindowId};
 
-use crate::models::{Camera, Model};
-use crate::renderer::{self, Renderer};
+use crate::gizmo::{Gizmo, GizmoMode};
+use crate::hot_reload::{self, ModelWatcher};
+use crate::picking::{self, Ray};
+use crate::renderer::{self, CullingMode, DebugView, Renderer};
+use crate::scene::{Scene, Selection};
+
+const BOUNDS_COLOR: [f32; 4] = [1.0, 0.9, 0.2, 1.0];
+/// Cursor travel in pixels under which a press and release is a click rather than a drag.
+const CLICK_TOLERANCE: f64 = 4.0;
+/// Keys selecting the debug views, in the order of `DebugView::ALL`.
+const DEBUG_VIEW_KEYS: [KeyCode; 8] = [
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+];
 
 pub fn run() -> Result<(), Box<dyn std::error::Error>> {
     let event_loop = EventLoop::new().unwrap();
@@ -27,11 +43,16 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
 struct MyWinitApp {
     state: Option<AppState<'static>>,
     dragging: (bool, Option<(f64, f64)>),
+    cursor_position: (f64, f64),
+    press_position: Option<(f64, f64)>,
 }
 struct AppState<'a> {
     window: Arc<Window>,
     renderer: Renderer<'a>,
     scene: Scene,
+    gizmo: Gizmo,
+    show_bounds: bool,
+    model_watcher: ModelWatcher,
 }
 impl<'a> AppState<'a> {
     fn window_size(&self) -> [u32; 2] {
@@ -40,6 +61,70 @@ impl<'a> AppState<'a> {
             self.window.inner_size().height,
         ]
     }
+    fn cursor_ray(&self, cursor_position: (f64, f64)) -> Ray {
+        let [width, height] = self.window_size();
+        Ray::from_viewport(
+            &self.scene.camera,
+            [cursor_position.0 as f32, cursor_position.1 as f32],
+            [width as f32, height as f32],
+        )
+    }
+    /// Selects the model under the cursor, or clears the selection when there is none.
+    fn pick(&mut self, cursor_position: (f64, f64)) {
+        let ray = self.cursor_ray(cursor_position);
+        let hit = picking::pick(&self.scene, &ray);
+        if let Some(hit) = &hit {
+            info!("Picked {hit:?}");
+        }
+        self.scene.selection = hit.map(|hit| Selection::Model(hit.model_idx));
+        self.window.request_redraw();
+    }
+    /// Starts dragging a gizmo handle of the selected model, returns false if none is under the
+    /// cursor.
+    fn begin_gizmo_drag(&mut self, cursor_position: (f64, f64)) -> bool {
+        let ray = self.cursor_ray(cursor_position);
+        match self.scene.selection {
+            Some(selection) => self.gizmo.begin_drag(
+                &self.scene.models[selection.model_idx()],
+                &self.scene.camera,
+                &ray,
+            ),
+            None => false,
+        }
+    }
+    /// Drags or hovers the gizmo handles of the selected model.
+    fn move_gizmo(&mut self, cursor_position: (f64, f64)) {
+        let ray = self.cursor_ray(cursor_position);
+        let Some(selection) = self.scene.selection else {
+            return;
+        };
+        let model = &mut self.scene.models[selection.model_idx()];
+        if self.gizmo.is_dragging() {
+            self.gizmo.drag(model, &ray);
+            self.window.request_redraw();
+        } else if self.gizmo.hover(model, &self.scene.camera, &ray) {
+            self.window.request_redraw();
+        }
+    }
+    fn render(&mut self) {
+        let gizmo_lines = match self.scene.selection {
+            Some(selection) => self.gizmo.lines(
+                &self.scene.models[selection.model_idx()],
+                &self.scene.camera,
+            ),
+            None => vec![],
+        };
+        self.renderer.set_overlay_lines(&gizmo_lines);
+        if self.show_bounds {
+            for model in self.scene.models.iter().filter(|model| model.visible) {
+                self.renderer
+                    .debug
+                    .depth_tested
+                    .bounds(&model.world_bounds(), BOUNDS_COLOR);
+            }
+        }
+        self.renderer.render(&self.scene).unwrap();
+    }
 }
 
 impl ApplicationHandler for MyWinitApp {
@@ -50,34 +135,69 @@ impl ApplicationHandler for MyWinitApp {
                 .unwrap(),
         );
         let viewport_size = [window.inner_size().width, window.inner_size().height];
-        let scene = Scene::new(viewport_size);
-        let (instance, _adapter, device, queue) = pollster::block_on(renderer::init());
+        // A scene description file can be given on the command line instead of the default scene
+        let scene = match std::env::args().nth(1) {
+            Some(path) => Scene::from_file(&path, viewport_size).unwrap_or_else(|load_error| {
+                error!("Loading the scene failed: {load_error}");
+                Scene::new(viewport_size)
+            }),
+            None => Scene::new(viewport_size),
+        };
+        let (instance, adapter, device, queue) = pollster::block_on(renderer::init());
         let mut renderer = Renderer::new(
             Cow::Owned(device),
             Cow::Owned(queue),
-            scene.textures_map.len(),
+            &adapter.get_info(),
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+            1,
         );
         let surface = instance.create_surface(window.clone()).unwrap();
         renderer.add_surface(viewport_size, surface);
+        renderer.load_scene(&scene);
         self.state = Some(AppState {
             window,
             renderer,
             scene,
+            gizmo: Gizmo::default(),
+            show_bounds: false,
+            model_watcher: ModelWatcher::default(),
         });
     }
 
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        if let Some(state) = &mut self.state {
+            let models_reloaded = state
+                .model_watcher
+                .reload_changed(&mut state.scene, &mut state.renderer);
+            let shaders_reloaded = state.renderer.reload_changed_shaders();
+            if models_reloaded || shaders_reloaded {
+                state.window.request_redraw();
+            }
+        }
+        // Wakes up to poll the files even without any event
+        event_loop.set_control_flow(ControlFlow::WaitUntil(
+            Instant::now() + hot_reload::POLL_INTERVAL,
+        ));
+    }
+
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
         match event {
             WindowEvent::CloseRequested => {
                 info!("Closing");
+                if let Some(state) = &self.state {
+                    state.renderer.save_pipeline_cache();
+                }
                 event_loop.exit();
             }
-            WindowEvent::RedrawRequested => {
-                let scene = &self.state.as_ref().unwrap().scene;
-                let renderer = &self.state.as_ref().unwrap().renderer;
-                let window_size = self.state.as_ref().unwrap().window_size();
-                renderer.render(window_size, scene).unwrap();
+            WindowEvent::Resized(size) => {
+                let state = self.state.as_mut().unwrap();
+                if size.width > 0 && size.height > 0 {
+                    state.renderer.resize([size.width, size.height]);
+                    state.scene.camera.aspect_ratio = size.width as f32 / size.height as f32;
+                    state.window.request_redraw();
+                }
             }
+            WindowEvent::RedrawRequested => self.state.as_mut().unwrap().render(),
             WindowEvent::KeyboardInput {
                 device_id: _device_id,
                 event,
@@ -99,86 +219,156 @@ impl ApplicationHandler for MyWinitApp {
                     self.state.as_mut().unwrap().scene.camera.rotate(-0.1, 0.0);
                     self.state.as_ref().unwrap().window.request_redraw();
                 }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyW) if event.state.is_pressed() => {
+                    self.state.as_mut().unwrap().gizmo.mode = GizmoMode::Translate;
+                    self.state.as_ref().unwrap().window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyE) if event.state.is_pressed() => {
+                    self.state.as_mut().unwrap().gizmo.mode = GizmoMode::Rotate;
+                    self.state.as_ref().unwrap().window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyR) if event.state.is_pressed() => {
+                    self.state.as_mut().unwrap().gizmo.mode = GizmoMode::Scale;
+                    self.state.as_ref().unwrap().window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyQ) if event.state.is_pressed() => {
+                    self.state.as_mut().unwrap().gizmo.toggle_space();
+                    self.state.as_ref().unwrap().window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyG) if event.state.is_pressed() => {
+                    let snapping = &mut self.state.as_mut().unwrap().gizmo.snapping;
+                    snapping.enabled = !snapping.enabled;
+                    info!("Snapping {}", if snapping.enabled { "on" } else { "off" });
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyH) if event.state.is_pressed() => {
+                    let state = self.state.as_mut().unwrap();
+                    state.renderer.grid.show_grid = !state.renderer.grid.show_grid;
+                    state.window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyF) if event.state.is_pressed() => {
+                    let state = self.state.as_mut().unwrap();
+                    state.scene.frame_selection();
+                    state.window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyB) if event.state.is_pressed() => {
+                    let state = self.state.as_mut().unwrap();
+                    state.show_bounds = !state.show_bounds;
+                    state.window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyC) if event.state.is_pressed() => {
+                    let state = self.state.as_mut().unwrap();
+                    let stats = state.renderer.culling_stats();
+                    info!(
+                        "Last frame drew {} meshes and culled {}",
+                        stats.drawn, stats.culled
+                    );
+                    let mode_idx = CullingMode::ALL
+                        .iter()
+                        .position(|&mode| mode == state.renderer.culling)
+                        .unwrap();
+                    state.renderer.culling =
+                        CullingMode::ALL[(mode_idx + 1) % CullingMode::ALL.len()];
+                    info!(
+                        "Frustum culling: {}",
+                        state.renderer.effective_culling().name()
+                    );
+                    state.window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyI) if event.state.is_pressed() => {
+                    // Toggles the selected model between a single instance and a grid of them
+                    let state = self.state.as_mut().unwrap();
+                    if let Some(selection) = state.scene.selection {
+                        let model = &mut state.scene.models[selection.model_idx()];
+                        let columns = if model.instances.is_empty() { 10 } else { 1 };
+                        model.instance_grid(columns, columns);
+                        state.renderer.refresh_scene(&state.scene);
+                        state.window.request_redraw();
+                    }
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyL) if event.state.is_pressed() => {
+                    let state = self.state.as_mut().unwrap();
+                    state.renderer.lod.enabled = !state.renderer.lod.enabled;
+                    info!(
+                        "Levels of detail: {}, last frame drew {} triangles",
+                        if state.renderer.lod.enabled {
+                            "on"
+                        } else {
+                            "off"
+                        },
+                        state.renderer.culling_stats().triangles
+                    );
+                    state.window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(KeyCode::KeyN) if event.state.is_pressed() => {
+                    let state = self.state.as_mut().unwrap();
+                    state.renderer.normals.enabled = !state.renderer.normals.enabled;
+                    state.window.request_redraw();
+                }
+                winit::keyboard::PhysicalKey::Code(code)
+                    if event.state.is_pressed() && DEBUG_VIEW_KEYS.contains(&code) =>
+                {
+                    let view_idx = DEBUG_VIEW_KEYS.iter().position(|&key| key == code).unwrap();
+                    let state = self.state.as_mut().unwrap();
+                    state.renderer.debug_view = DebugView::ALL[view_idx];
+                    info!("Debug view: {}", state.renderer.debug_view.name());
+                    state.window.request_redraw();
+                }
                 _ => {}
             },
             WindowEvent::MouseInput {
                 device_id,
                 state,
                 button,
-            } => self.dragging = (state.is_pressed(), None),
-            WindowEvent::CursorMoved {
-                device_id,
-                position,
             } => {
-                if self.dragging.0 {
-                    match self.dragging.1 {
-                        Some(previous_position) => {
-                            let delta_x = position.x - previous_position.0;
-                            let delta_y = position.y - previous_position.1;
-                            self.state
-                                .as_mut()
-                                .unwrap()
-                                .scene
-                                .camera
-                                .rotate((-delta_y / 200.0) as f32, (-delta_x / 200.0) as f32);
-                            self.state.as_ref().unwrap().window.request_redraw();
-                            self.dragging.1 = Some((position.x, position.y))
+                self.dragging = (state.is_pressed(), None);
+                if button == MouseButton::Left {
+                    let app_state = self.state.as_mut().unwrap();
+                    if state.is_pressed() {
+                        if app_state.begin_gizmo_drag(self.cursor_position) {
+                            // The gizmo takes over the drag, neither rotating nor picking
+                            self.dragging = (false, None);
+                        } else {
+                            self.press_position = Some(self.cursor_position);
+                        }
+                    } else if app_state.gizmo.is_dragging() {
+                        app_state.gizmo.end_drag();
+                        app_state.window.request_redraw();
+                    } else if let Some(press_position) = self.press_position.take() {
+                        // Only a click if the cursor barely moved, otherwise the camera was dragged
+                        let moved = (self.cursor_position.0 - press_position.0).abs()
+                            + (self.cursor_position.1 - press_position.1).abs();
+                        if moved < CLICK_TOLERANCE {
+                            app_state.pick(self.cursor_position);
                         }
-                        None => self.dragging.1 = Some((position.x, position.y)),
                     }
                 }
             }
-            _ => {}
-        }
-    }
-}
-
-pub struct Scene {
-    pub models: Vec<Model>,
-    pub textures_map: IndexMap<String, RgbaImage>,
-    pub camera: Camera,
-}
-impl Scene {
-    fn new(viewport_dimensions: [u32; 2]) -> Self {
-        let teapot = Model::new(
-            "./models/teapot.obj",
-            (
-                Vector3::new(1.0, 1.0, 1.0),
-                Vector3::default(),
-                Vector3::new(0.01, 0.01, 0.01),
-            ),
-        );
-        let cube = Model::new(
-            "./models/cube.obj",
-            (
-                Vector3::new(-1.0, -1.0, -1.0),
-                Vector3::default(),
-                Vector3::new(1.0, 1.0, 1.0),
-            ),
-        );
-        let models = vec![teapot, cube];
-        let mut textures_map = IndexMap::new();
-        for model in &models {
-            for material in &model.materials {
-                match &material.diffuse_texture {
-                    Some(dt_name) => {
-                        let dt_path = format!("./models/{dt_name}");
-                        let dt_data = ImageReader::open(&dt_path)
-                            .unwrap()
-                            .decode()
+            WindowEvent::CursorMoved {
+                device_id,
+                position,
+            } => {
+                self.cursor_position = (position.x, position.y);
+                if self.dragging.0 {
+                    if let Some(previous_position) = self.dragging.1 {
+                        let delta_x = position.x - previous_position.0;
+                        let delta_y = position.y - previous_position.1;
+                        self.state
+                            .as_mut()
                             .unwrap()
-                            .to_rgba8();
-                        textures_map.insert(material.name.clone(), dt_data);
+                            .scene
+                            .camera
+                            .rotate((-delta_y / 200.0) as f32, (-delta_x / 200.0) as f32);
+                        self.state.as_ref().unwrap().window.request_redraw();
                     }
-                    None => {}
+                    self.dragging.1 = Some((position.x, position.y))
+                } else {
+                    self.state
+                        .as_mut()
+                        .unwrap()
+                        .move_gizmo(self.cursor_position);
                 }
             }
-        }
-        let camera = Camera::new(viewport_dimensions[0] as f32 / viewport_dimensions[1] as f32);
-        Self {
-            models,
-            camera,
-            textures_map,
+            _ => {}
         }
     }
 }